@@ -0,0 +1,78 @@
+// $ cargo rb din_midi
+// DIN MIDI thru on USART1 (TX PA9, RX PA10), received messages are logged and echoed
+#![no_main]
+#![no_std]
+
+use f103_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
+mod app {
+    use f103_rtic::midi::{din, din::DinMidi, MidiMessage};
+    use stm32f1xx_hal::{
+        pac::USART1,
+        prelude::*,
+        serial::{Config, Event::Rxne, Rx, Serial, Tx},
+    };
+
+    #[shared]
+    struct Shared {
+        din: DinMidi<Tx<USART1>, Rx<USART1>>,
+    }
+
+    #[local]
+    struct Local {}
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let rcc = ctx.device.RCC.constrain();
+        let mut flash = ctx.device.FLASH.constrain();
+        let clocks = rcc.cfgr.freeze(&mut flash.acr);
+        let mut afio = ctx.device.AFIO.constrain();
+        let mut gpioa = ctx.device.GPIOA.split();
+        let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
+        let rx = gpioa.pa10;
+        let mut serial = Serial::usart1(
+            ctx.device.USART1,
+            (tx, rx),
+            &mut afio.mapr,
+            Config::default().baudrate(din::BAUD_RATE.bps()),
+            clocks,
+        );
+        serial.listen(Rxne);
+        let (tx, rx) = serial.split();
+        defmt::info!("Send me MIDI");
+        (
+            Shared {
+                din: DinMidi::new(tx, rx),
+            },
+            Local {},
+            init::Monotonics(),
+        )
+    }
+
+    // Drains the transmit buffer
+    #[idle(shared = [din])]
+    fn idle(mut ctx: idle::Context) -> ! {
+        loop {
+            ctx.shared.din.lock(|din| din.flush().ok());
+        }
+    }
+
+    // Triggers on RX not empty
+    #[task(binds = USART1, shared = [din], priority = 2)]
+    fn on_rx(mut ctx: on_rx::Context) {
+        ctx.shared.din.lock(|din| {
+            while let Ok(msg) = din.read() {
+                echo::spawn(msg).ok();
+            }
+        });
+    }
+
+    #[task(shared = [din], priority = 1, capacity = 8)]
+    fn echo(mut ctx: echo::Context, msg: MidiMessage) {
+        defmt::info!("Received {}", msg);
+        if ctx.shared.din.lock(|din| din.send(&msg)).is_err() {
+            defmt::info!("Dropped {}", msg);
+        }
+    }
+}
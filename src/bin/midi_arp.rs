@@ -0,0 +1,213 @@
+// DEFMT_LOG=info cargo rrb midi_arp
+//
+// Arpeggiator, notes held on the host (received on the USB OUT endpoint) are played
+// back one after the other on channel 0. All Notes Off (CC 123) releases them all.
+// The on-board LED is on while a note sounds.
+//
+// The arpeggiator is polled by a task scheduled on a TIM2 based 1 MHz monotonic, which
+// is rescheduled when a note arrives, so playback starts right away.
+
+#![no_std]
+#![no_main]
+
+use f103_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
+mod app {
+    use cortex_m::asm::delay;
+    use f103_rtic::midi::{
+        self,
+        arpeggiator::{ArpMode, Arpeggiator},
+        MidiClass, MidiMessage,
+    };
+    use fugit::TimerInstantU32;
+    use stm32f1xx_hal::{
+        gpio::{gpioc::PC13, Output, PushPull},
+        pac,
+        prelude::*,
+        timer::{MonoTimer, MonoTimerExt},
+        usb::{Peripheral, UsbBus, UsbBusType},
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+
+    const BPM: u32 = 120;
+    const MODE: ArpMode = ArpMode::UpDown;
+    const OCTAVES: u8 = 2;
+    // steps per beat, 4 for sixteenths
+    const RATE: u32 = 4;
+    // percent of a step
+    const GATE: u8 = 60;
+
+    // All Notes Off
+    const ALL_NOTES_OFF: u8 = 123;
+
+    #[monotonic(binds = TIM2, default = true)]
+    type MicrosMono = MonoTimer<pac::TIM2, 1_000_000>;
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+        arp: Arpeggiator,
+        next_play: Option<play::SpawnHandle>,
+    }
+
+    #[local]
+    struct Local {
+        led: PC13<Output<PushPull>>,
+    }
+
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        let p = ctx.device;
+
+        let rcc = p.RCC.constrain();
+        let mut flash = p.FLASH.constrain();
+
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .freeze(&mut flash.acr);
+
+        assert!(clocks.usbclk_valid(), "usb clocks not valid");
+
+        let mono = p.TIM2.monotonic_us(&clocks);
+
+        let mut gpioa = p.GPIOA.split();
+        let mut gpioc = p.GPIOC.split();
+
+        // Configure the on-board LED (PC13, green)
+        let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
+        led.set_high(); // Turn off
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb = Peripheral {
+            usb: p.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
+
+        let midi = MidiClass::new(usb_bus);
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
+            .manufacturer("Fake company")
+            .product("MIDI Arp")
+            .serial_number("TEST")
+            .device_class(midi::USB_CLASS_AUDIO)
+            .build();
+
+        let mut arp = Arpeggiator::new(0, BPM);
+        arp.set_mode(MODE);
+        arp.set_octaves(OCTAVES);
+        arp.set_rate(RATE);
+        arp.set_gate(GATE);
+
+        (
+            Shared {
+                usb_dev,
+                midi,
+                arp,
+                next_play: None,
+            },
+            Local { led },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Sends the messages that are due, and schedules itself for the next ones
+    #[task(shared = [midi, arp, next_play], local = [led])]
+    fn play(ctx: play::Context) {
+        let led = ctx.local.led;
+        let now = monotonics::now().ticks();
+
+        (ctx.shared.midi, ctx.shared.arp, ctx.shared.next_play).lock(|midi, arp, next_play| {
+            while let Some(msg) = arp.poll(now) {
+                match msg {
+                    MidiMessage::NoteOn { .. } => led.set_low(),
+                    _ => led.set_high(),
+                }
+                defmt::trace!("{}", msg);
+                if midi.send(msg).is_err() {
+                    defmt::info!("dropped {}", msg);
+                }
+            }
+            *next_play = arp
+                .next_due(now)
+                .and_then(|at| play::spawn_at(TimerInstantU32::from_ticks(at)).ok());
+        });
+    }
+
+    // Messages received from the host
+    #[task(shared = [arp, next_play], capacity = 8)]
+    fn on_midi(ctx: on_midi::Context, msg: MidiMessage) {
+        let now = monotonics::now().ticks();
+
+        (ctx.shared.arp, ctx.shared.next_play).lock(|arp, next_play| {
+            match msg {
+                MidiMessage::ControlChange {
+                    control: ALL_NOTES_OFF,
+                    ..
+                } => arp.release_all(),
+                _ => {
+                    if !arp.feed(&msg, now) {
+                        defmt::debug!("ignored {}", msg);
+                        return;
+                    }
+                }
+            }
+
+            // play now if this was the first note, otherwise nothing changes
+            if let Some(at) = arp.next_due(now) {
+                let at = TimerInstantU32::from_ticks(at);
+                *next_play = match next_play.take() {
+                    Some(handle) => handle.reschedule_at(at).ok(),
+                    None => play::spawn_at(at).ok(),
+                };
+            }
+        });
+    }
+
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+    ) {
+        if !usb_dev.poll(&mut [midi]) {
+            return;
+        }
+
+        match midi.poll_events() {
+            Ok(packets) => {
+                for msg in packets.filter_map(|packet| packet.message()) {
+                    if on_midi::spawn(msg).is_err() {
+                        defmt::info!("dropped {}", msg);
+                    }
+                }
+            }
+            Err(_) => defmt::info!("read error"),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// DEFMT_LOG=info cargo rrb midi_buttons
+//
+// Push buttons on PB12..PB15 (to ground), sending MIDI as set in `MAPPINGS`:
+// - PB12 plays note C4 while held
+// - PB13 holds the sustain pedal (CC 64) while held
+// - PB14 toggles CC 80, a long press toggles CC 81
+// - PB15 selects program 1, a double press program 2 and a long press program 0
+//
+// The buttons are scanned every `SCAN_US` by a task on a TIM2 based 1 MHz monotonic,
+// and debounced in software by `f103_rtic::buttons::Button`. The on-board LED is on
+// while a button is pressed.
+
+#![no_std]
+#![no_main]
+
+use f103_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
+mod app {
+    use cortex_m::asm::delay;
+    use f103_rtic::{
+        buttons::{Action, Button, Mapping},
+        midi::{self, MidiClass},
+    };
+    use fugit::ExtU32;
+    use stm32f1xx_hal::{
+        gpio::{
+            gpiob::{PB12, PB13, PB14, PB15},
+            gpioc::PC13,
+            Input, Output, PullUp, PushPull,
+        },
+        pac,
+        prelude::*,
+        timer::{MonoTimer, MonoTimerExt},
+        usb::{Peripheral, UsbBus, UsbBusType},
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+
+    const SCAN_US: u32 = 2_000;
+
+    const CHANNEL: u8 = 0;
+
+    const NR_BUTTONS: usize = 4;
+
+    const MAPPINGS: [Mapping; NR_BUTTONS] = [
+        Mapping {
+            press: Some(Action::Note {
+                channel: CHANNEL,
+                note: 60,
+                velocity: 100,
+            }),
+            long: None,
+            double: None,
+        },
+        Mapping {
+            press: Some(Action::MomentaryCc {
+                channel: CHANNEL,
+                cc: 64,
+            }),
+            long: None,
+            double: None,
+        },
+        Mapping {
+            press: Some(Action::ToggleCc {
+                channel: CHANNEL,
+                cc: 80,
+            }),
+            long: Some(Action::ToggleCc {
+                channel: CHANNEL,
+                cc: 81,
+            }),
+            double: None,
+        },
+        Mapping {
+            press: Some(Action::ProgramChange {
+                channel: CHANNEL,
+                program: 1,
+            }),
+            long: Some(Action::ProgramChange {
+                channel: CHANNEL,
+                program: 0,
+            }),
+            double: Some(Action::ProgramChange {
+                channel: CHANNEL,
+                program: 2,
+            }),
+        },
+    ];
+
+    #[monotonic(binds = TIM2, default = true)]
+    type MicrosMono = MonoTimer<pac::TIM2, 1_000_000>;
+
+    type Instant = fugit::TimerInstantU32<1_000_000>;
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+    }
+
+    #[local]
+    struct Local {
+        pins: (
+            PB12<Input<PullUp>>,
+            PB13<Input<PullUp>>,
+            PB14<Input<PullUp>>,
+            PB15<Input<PullUp>>,
+        ),
+        led: PC13<Output<PushPull>>,
+    }
+
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        let p = ctx.device;
+
+        let rcc = p.RCC.constrain();
+        let mut flash = p.FLASH.constrain();
+
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .freeze(&mut flash.acr);
+
+        assert!(clocks.usbclk_valid(), "usb clocks not valid");
+
+        let mono = p.TIM2.monotonic_us(&clocks);
+
+        let mut gpioa = p.GPIOA.split();
+        let mut gpiob = p.GPIOB.split();
+        let mut gpioc = p.GPIOC.split();
+
+        // Configure the buttons, pressed when low
+        let pins = (
+            gpiob.pb12.into_pull_up_input(&mut gpiob.crh),
+            gpiob.pb13.into_pull_up_input(&mut gpiob.crh),
+            gpiob.pb14.into_pull_up_input(&mut gpiob.crh),
+            gpiob.pb15.into_pull_up_input(&mut gpiob.crh),
+        );
+
+        // Configure the on-board LED (PC13, green)
+        let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
+        led.set_high(); // Turn off
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb = Peripheral {
+            usb: p.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
+
+        let midi = MidiClass::new(usb_bus);
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
+            .manufacturer("Fake company")
+            .product("MIDI Buttons")
+            .serial_number("TEST")
+            .device_class(midi::USB_CLASS_AUDIO)
+            .build();
+
+        scan::spawn().ok();
+
+        (
+            Shared { usb_dev, midi },
+            Local { pins, led },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Reads the buttons and sends what their gestures are mapped to, every `SCAN_US`
+    #[task(
+        shared = [usb_dev, midi],
+        local = [
+            pins,
+            led,
+            due: Option<Instant> = None,
+            buttons: Option<[Button; NR_BUTTONS]> = None,
+        ],
+        priority = 1
+    )]
+    fn scan(ctx: scan::Context) {
+        let due = *ctx.local.due.get_or_insert_with(monotonics::now);
+        let buttons = ctx
+            .local
+            .buttons
+            .get_or_insert_with(|| MAPPINGS.map(Button::new));
+
+        let pins = &ctx.local.pins;
+        let pressed = [
+            pins.0.is_low(),
+            pins.1.is_low(),
+            pins.2.is_low(),
+            pins.3.is_low(),
+        ];
+
+        let now = due.ticks();
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(|usb_dev, midi| {
+            let configured = usb_dev.state() == UsbDeviceState::Configured;
+            for (i, (button, pressed)) in buttons.iter_mut().zip(pressed).enumerate() {
+                for msg in button.poll(pressed, now) {
+                    defmt::info!("button {}: {}", i, msg);
+                    if configured && midi.send(msg).is_err() {
+                        defmt::info!("dropped {}", msg);
+                    }
+                }
+            }
+        });
+
+        if buttons.iter().any(|button| button.is_pressed()) {
+            ctx.local.led.set_low();
+        } else {
+            ctx.local.led.set_high();
+        }
+
+        let next = due + SCAN_US.micros();
+        *ctx.local.due = Some(next);
+        scan::spawn_at(next).ok();
+    }
+
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+    ) {
+        if !usb_dev.poll(&mut [midi]) {
+            return;
+        }
+
+        // nothing to do with messages from the host, just keep the endpoint going
+        if let Ok(packets) = midi.poll_events() {
+            for packet in packets {
+                defmt::debug!("ignored {}", packet);
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// DEFMT_LOG=info cargo rrb midi_clock
+//
+// MIDI clock master, sends Start once the host configured the device, followed by
+// 24 timing clocks per quarter note at `BPM`. The on-board LED flashes on every beat.
+//
+// Clocks are scheduled at absolute instants of a TIM2 based 1 MHz monotonic, so the
+// tempo does not drift and the jitter is in the range of the interrupt latency.
+
+#![no_std]
+#![no_main]
+
+use f103_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
+mod app {
+    use cortex_m::asm::delay;
+    use f103_rtic::midi::{self, clock::ClockMaster, clock::PPQN, MidiClass};
+    use fugit::ExtU32;
+    use stm32f1xx_hal::{
+        gpio::{gpioc::PC13, Output, PushPull},
+        pac,
+        prelude::*,
+        timer::{MonoTimer, MonoTimerExt},
+        usb::{Peripheral, UsbBus, UsbBusType},
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+
+    const BPM: u32 = 120;
+
+    #[monotonic(binds = TIM2, default = true)]
+    type MicrosMono = MonoTimer<pac::TIM2, 1_000_000>;
+
+    type Instant = fugit::TimerInstantU32<1_000_000>;
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+        clock: ClockMaster,
+    }
+
+    #[local]
+    struct Local {
+        led: PC13<Output<PushPull>>,
+    }
+
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        let p = ctx.device;
+
+        let rcc = p.RCC.constrain();
+        let mut flash = p.FLASH.constrain();
+
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .freeze(&mut flash.acr);
+
+        assert!(clocks.usbclk_valid(), "usb clocks not valid");
+
+        let mono = p.TIM2.monotonic_us(&clocks);
+
+        let mut gpioa = p.GPIOA.split();
+        let mut gpioc = p.GPIOC.split();
+
+        // Configure the on-board LED (PC13, green)
+        let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
+        led.set_high(); // Turn off
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb = Peripheral {
+            usb: p.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
+
+        let midi = MidiClass::with_ports(usb_bus, &["Clock"]);
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
+            .manufacturer("Fake company")
+            .product("MIDI Clock")
+            .serial_number("TEST")
+            .device_class(midi::USB_CLASS_AUDIO)
+            .build();
+
+        tick::spawn().ok();
+
+        (
+            Shared {
+                usb_dev,
+                midi,
+                clock: ClockMaster::new(BPM),
+            },
+            Local { led },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Sends a timing clock and schedules the next one relative to when this one
+    // was due, not when it actually ran, so latency does not add up
+    #[task(
+        shared = [usb_dev, midi, clock],
+        local = [led, due: Option<Instant> = None, count: u32 = 0],
+        priority = 2
+    )]
+    fn tick(ctx: tick::Context) {
+        let due = *ctx.local.due.get_or_insert_with(monotonics::now);
+        let count = ctx.local.count;
+        let led = ctx.local.led;
+
+        let interval =
+            (ctx.shared.usb_dev, ctx.shared.midi, ctx.shared.clock).lock(|usb_dev, midi, clock| {
+                let configured = usb_dev.state() == UsbDeviceState::Configured;
+                if configured != clock.is_running() {
+                    // start with the host, stop when it goes away
+                    let msg = if configured {
+                        *count = 0;
+                        clock.start()
+                    } else {
+                        clock.stop()
+                    };
+                    defmt::info!("{}", msg);
+                    midi.send(msg).ok();
+                }
+
+                let (msg, interval) = clock.tick();
+                if configured {
+                    midi.send(msg).ok();
+                }
+                interval
+            });
+
+        // flash the LED for the first 1/8 of every beat
+        match *count % PPQN {
+            0 => led.set_low(),
+            3 => led.set_high(),
+            _ => {}
+        }
+        *count = count.wrapping_add(1);
+
+        let next = due + interval.micros();
+        *ctx.local.due = Some(next);
+        tick::spawn_at(next).ok();
+    }
+
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+    ) {
+        if !usb_dev.poll(&mut [midi]) {
+            return;
+        }
+
+        // nothing to do with messages from the host, just keep the endpoint going
+        if let Ok(packets) = midi.poll_events() {
+            for packet in packets {
+                defmt::debug!("ignored {}", packet);
+            }
+        }
+    }
+}
@@ -1,126 +1,82 @@
-// DEFMT_LOG=info cargo rrb midi_raw
+// DEFMT_LOG=info cargo rrb midi_ctrl
+//
+// Sends the positions of up to 10 potentiometers as CCs, by default CC 1, 2, 3 ... on
+// channel 0. The first `NR_CONTROLS` of PB0, PB1, PA0..PA7 are scanned by ADC1, with DMA
+// into a buffer.
+// With `HIGH_RES` the full 12 bit reading is sent as a 14 bit CC (e.g. CC 1 MSB, CC 33 LSB).
+// The channel, CC numbers, response curves and thresholds are set over SysEx (see
+// `f103_rtic::settings::protocol`) and kept in flash.
+// MIDI learn: press the button on PB12 (to ground) or send `F0 7D 7F 06 F7`, the LED
+// lights up, move a pot and send a CC from the host. The pot is then bound to the
+// channel and CC number of that message.
+// Calibration: send `F0 7D 7F 08 F7`, the LED lights up, turn every pot from end to end
+// and send `F0 7D 7F 09 F7` to store the ranges.
+// Pickup mode (`F0 7D 7F 02 08 00 01 00 F7`): after a value changed on the host, a pot
+// only sends once it reaches that value, the LED is on while a pot waits to be picked up.
+// Note on/off messages from the host turn the on-board LED on/off.
+// Universal SysEx Identity Requests (`F0 7E 7F 06 01 F7`) are answered with `IDENTITY`.
 
 #![no_std]
 #![no_main]
 
 use f103_rtic as _; // global logger + panicking-behavior + memory layout
 
-mod midi {
-    use usb_device::class_prelude::*;
-    use usb_device::Result;
-
-    pub const USB_CLASS_AUDIO: u8 = 0x01;
-
-    pub struct MidiClass<'a, B: UsbBus> {
-        audio_if: InterfaceNumber,
-        midi_if: InterfaceNumber,
-        out_ep: EndpointOut<'a, B>,
-        in_ep: EndpointIn<'a, B>,
-    }
-
-    impl<B: UsbBus> MidiClass<'_, B> {
-        pub fn new(alloc: &UsbBusAllocator<B>) -> MidiClass<'_, B> {
-            MidiClass {
-                audio_if: alloc.interface(),
-                midi_if: alloc.interface(),
-                out_ep: alloc.bulk(64),
-                in_ep: alloc.bulk(64),
-            }
-        }
-
-        pub fn note_off(&self, chan: u8, key: u8, vel: u8) -> Result<usize> {
-            // I have no idea why the "virtual cable" must be number 0 and not one of the jack IDs
-            // but only 0 seemed to work
-            self.in_ep
-                .write(&[0x08, 0x80 | (chan & 0x0f), key & 0x7f, vel & 0x7f])
-        }
-
-        pub fn note_on(&self, chan: u8, key: u8, vel: u8) -> Result<usize> {
-            self.in_ep
-                .write(&[0x09, 0x90 | (chan & 0x0f), key & 0x7f, vel & 0x7f])
-        }
-
-        pub fn ctrl(&self, chan: u8, ctrl_nr: u8, ctrl_data: u8) -> Result<usize> {
-            self.in_ep
-                .write(&[0x0b, 0xb0 | (chan & 0x0f), ctrl_nr & 0x7f, ctrl_data & 0x7f])
-        }
-    }
-
-    impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
-        fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-            writer.interface(self.audio_if, 0x01, 0x01, 0x00)?; // Interface 0
-            writer.write(
-                0x24, /* interface decscriptor */
-                &[0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01],
-            )?; // CS Interface (audio)
-
-            writer.interface(self.midi_if, 0x01, 0x03, 0x00)?; // Interface 1
-            writer.write(0x24, &[0x01, 0x00, 0x01, 0x2e, 0x00])?; // CS Interface (midi)
-
-            writer.write(
-                0x24, /* Descriptor */
-                &[
-                    0x02, /* midi jack in */
-                    0x01, /* embedded */
-                    0x01, /* id */
-                    0x00, /* unused */
-                ],
-            )?; // IN Jack 1 (emb)
-            writer.write(
-                0x24, /* Descriptor */
-                &[
-                    0x03, /* midi jack out */
-                    // 0x01 /* embedded */,
-                    0x02, /* external */
-                    0x02, /* id */
-                    // 0x01 /* nr of input pins */,
-                    0x00, /* nr of input pins */
-                    // 0x01 /* id of entity this this pin is connected to */,
-                    // 0x01 /* output pin number number of the entity to which this pin is connected */,
-                    0x00,
-                ], /* unused */
-            )?; // OUT Jack 2 (emb)
-
-            writer.endpoint(&self.out_ep)?;
-            writer.write(0x25, &[0x01, 0x01, 0x01])?; // CS EP IN Jack
-
-            writer.endpoint(&self.in_ep)?;
-            writer.write(0x25, &[0x01, 0x01, 0x02])?; // CS EP OUT Jack
-
-            Ok(())
-        }
-    }
-}
-
 #[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
 mod app {
     use cortex_m::asm::delay;
-    // use nb::block;
-    //  use stm32f103xx_usb::UsbBus;
-    use super::midi;
+    use f103_rtic::{
+        filter::{Filter, Hysteresis, Median, MovingAverage},
+        midi::{
+            self, sysex, Identity, ManufacturerId, MidiClass, MidiMessage, OverflowPolicy,
+            ParameterTracker, SysExReceiver,
+        },
+        pickup::Pickup,
+        settings::{self, calibration::Calibration, learn::Learn, protocol, Settings},
+    };
+    use heapless::Vec;
     use stm32f1xx_hal::{
-        adc,
+        adc::{self, AdcDma, ChannelTimeSequence, Scan, SetChannels},
+        flash,
+        gpio::{
+            gpioa::{PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7},
+            gpiob::{PB0, PB1, PB12},
+            gpioc::PC13,
+            Analog, Input, Output, PullUp, PushPull,
+        },
         pac,
         prelude::*,
-        usb::{Peripheral, UsbBus},
-        // dma::{
-        //     dma1::{C4, C5},
-        //     Event, RxDma, Transfer, TxDma, R, W,
-        // },
-        // pac::USART1,
-        // prelude::*,
-        // serial::{Config, Rx, Serial, Tx}
+        usb::{Peripheral, UsbBus, UsbBusType},
     };
-    use stm32f1xx_hal::{prelude::*, stm32, timer::Timer};
-    use usb_device::prelude::*;
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
 
     #[shared]
-    struct Shared {}
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+        sysex_rx: SysExReceiver<{ protocol::MAX_REQUEST_LEN }>,
+        // SysEx reply waiting for room in the queue
+        reply: Option<Vec<u8, { protocol::MAX_REPLY_LEN }>>,
+        settings: Settings,
+        learn: Learn,
+        calibration: Calibration,
+        pickup: Pickup<NR_CONTROLS>,
+        // a note is held on the host
+        note_on: bool,
+    }
 
     #[local]
-    struct Local {}
+    struct Local {
+        // the adc with the buffer for a scan, taken by idle
+        scan: Option<(AdcDma<Pots, Scan>, &'static mut [u16; NR_CONTROLS])>,
+        learn_button: PB12<Input<PullUp>>,
+        led: PC13<Output<PushPull>>,
+        flash: flash::Parts,
+    }
 
-    #[init()]
+    #[init(local = [
+        usb_bus: Option<UsbBusAllocator<UsbBusType>> = None,
+        adc_buf: [u16; NR_CONTROLS] = [0; NR_CONTROLS],
+    ])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("init");
 
@@ -139,24 +95,51 @@ mod app {
 
         assert!(clocks.usbclk_valid(), "usb clocks not valid");
 
-        // Setup ADC
-        let mut adc1 = adc::Adc::adc1(p.ADC1, clocks);
+        let settings = match settings::flash::load(&mut flash) {
+            Some(settings) if settings.cc_within(MAX_CC) => settings,
+            Some(_) => {
+                defmt::info!("stored CC numbers have no 14 bit pair, using the defaults");
+                default_settings()
+            }
+            None => {
+                defmt::info!("no stored settings, using the defaults");
+                default_settings()
+            }
+        };
+        defmt::debug!("{}", settings);
 
-        // Setup GPIOB
+        let mut gpioa = p.GPIOA.split();
         let mut gpiob = p.GPIOB.split();
 
-        // Configure pb0, pb1 as an analog input
-        let mut ch0 = gpiob.pb0.into_analog(&mut gpiob.crl);
+        // Configure the pots as analog inputs
+        let pots = Pots(
+            gpiob.pb0.into_analog(&mut gpiob.crl),
+            gpiob.pb1.into_analog(&mut gpiob.crl),
+            gpioa.pa0.into_analog(&mut gpioa.crl),
+            gpioa.pa1.into_analog(&mut gpioa.crl),
+            gpioa.pa2.into_analog(&mut gpioa.crl),
+            gpioa.pa3.into_analog(&mut gpioa.crl),
+            gpioa.pa4.into_analog(&mut gpioa.crl),
+            gpioa.pa5.into_analog(&mut gpioa.crl),
+            gpioa.pa6.into_analog(&mut gpioa.crl),
+            gpioa.pa7.into_analog(&mut gpioa.crl),
+        );
+
+        // Setup ADC, scanning the pots into `adc_buf` with DMA1 channel 1
+        let dma1 = p.DMA1.split();
+        let adc1 = adc::Adc::adc1(p.ADC1, clocks);
+        let adc_dma = adc1.with_scan_dma(pots, dma1.1);
+
+        // Configure pb12 as the learn button, pressed when low
+        let learn_button = gpiob.pb12.into_pull_up_input(&mut gpiob.crh);
 
         // Setup LED
         let mut gpioc = p.GPIOC.split();
         // Configure the on-board LED (PC13, green)
         let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
-        led.set_low(); // Turn on
+        led.set_high(); // Turn off
 
         // Setup USB
-        let mut gpioa = p.GPIOA.split();
-
         // BluePill board has a pull-up resistor on the D+ line.
         // Pull the D+ pin down to send a RESET condition to the USB bus.
         // This forced reset is needed only for development, without it host
@@ -171,65 +154,434 @@ mod app {
             pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
         };
 
-        let usb_bus = UsbBus::new(usb);
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
 
-        let mut midi = midi::MidiClass::new(&usb_bus);
+        let mut midi = MidiClass::new(usb_bus);
+        // if the host does not keep up, only send the latest knob position
+        midi.set_overflow_policy(OverflowPolicy::Coalesce);
+        midi.set_identity(DEVICE_ID, IDENTITY);
 
-        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27de))
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
             .manufacturer("Wha wha wha")
             .product("MIDI Wha")
             .serial_number("0.1.0")
             .device_class(midi::USB_CLASS_AUDIO)
             .build();
 
-        let mut old_data_msb = 0;
+        (
+            Shared {
+                usb_dev,
+                midi,
+                sysex_rx: SysExReceiver::new(),
+                reply: None,
+                settings,
+                learn: Learn::new().with_max_cc(MAX_CC),
+                calibration: Calibration::new(),
+                pickup: Pickup::new(PICKUP_WINDOW),
+                note_on: false,
+            },
+            Local {
+                scan: Some((adc_dma, ctx.local.adc_buf)),
+                learn_button,
+                led,
+                flash,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    // number of pots, scanned in the order of `CHANNELS`
+    const NR_CONTROLS: usize = 8;
+
+    // ADC1 channels of PB0, PB1, PA0..PA7, the pots of controls 0, 1, 2 ...
+    const CHANNELS: [u8; settings::MAX_CONTROLS] = [8, 9, 0, 1, 2, 3, 4, 5, 6, 7];
+
+    // all pins a pot can be connected to, only the first `NR_CONTROLS` are scanned
+    pub struct Pots(
+        PB0<Analog>,
+        PB1<Analog>,
+        PA0<Analog>,
+        PA1<Analog>,
+        PA2<Analog>,
+        PA3<Analog>,
+        PA4<Analog>,
+        PA5<Analog>,
+        PA6<Analog>,
+        PA7<Analog>,
+    );
+
+    impl SetChannels<Pots> for adc::Adc<pac::ADC1> {
+        fn set_samples(&mut self) {
+            for channel in CHANNELS {
+                self.set_channel_sample_time(channel, adc::SampleTime::T_28);
+            }
+        }
+
+        fn set_sequence(&mut self) {
+            self.set_regular_sequence(&CHANNELS[..NR_CONTROLS]);
+        }
+    }
+
+    // SysEx device ID, for Identity Requests and settings
+    const DEVICE_ID: u8 = sysex::ALL_CALL;
+
+    // reported to Identity Requests, along with the crate version
+    const IDENTITY: Identity = Identity::new(ManufacturerId::NON_COMMERCIAL, 0x0001, 0x0001);
+
+    // 14 bit (MSB/LSB controller pair) instead of 7 bit CC values, the CC number
+    // must be below 32
+    const HIGH_RES: bool = false;
+
+    // highest CC number controls can be bound to, by learn and over SysEx
+    const MAX_CC: u8 = if HIGH_RES {
+        settings::MAX_CC_14BIT
+    } else {
+        settings::MAX_CC
+    };
+
+    // more averaging in 14 bit mode, where the adc noise is no longer hidden by
+    // the truncation to 7 bits
+    const NR_SAMPLES: usize = if HIGH_RES { 16 } else { 4 };
+
+    // 12 bit adc reading to 14 or 7 bits
+    const fn to_value(raw: u16) -> u16 {
+        if HIGH_RES {
+            raw << 2
+        } else {
+            raw >> 5
+        }
+    }
+
+    const MAX_VALUE: u16 = to_value(4095);
+
+    // host values this close to a pot count as reached in pickup mode
+    const PICKUP_WINDOW: u16 = if HIGH_RES { 1 << 7 } else { 1 };
+
+    // the filters of a pot, the threshold of the control is the hysteresis band
+    struct Pot {
+        median: Median<u16, 3>,
+        average: MovingAverage<u16, NR_SAMPLES>,
+        hysteresis: Hysteresis<u16>,
+    }
+
+    impl Pot {
+        fn new() -> Self {
+            Pot {
+                median: Median::new(),
+                average: MovingAverage::new(),
+                hysteresis: Hysteresis::new(0).with_limits(0, MAX_VALUE),
+            }
+        }
+    }
+
+    // used until settings are stored, with a wider threshold in 14 bit mode for the
+    // same reason
+    fn default_settings() -> Settings {
+        let mut settings = Settings::default();
+        if HIGH_RES {
+            for control in settings.controls.iter_mut() {
+                control.threshold = 8;
+            }
+        }
+        settings
+    }
+
+    #[idle(shared = [usb_dev, midi, settings, learn, calibration, pickup, note_on], local = [scan, learn_button, led])]
+    fn idle(mut ctx: idle::Context) -> ! {
+        let (mut adc_dma, mut buf) = ctx.local.scan.take().unwrap();
+
+        let mut pots = [(); NR_CONTROLS].map(|_| Pot::new());
 
-        let mut send = false;
+        let mut waiting = false;
 
-        const NR_SAMPLES: u32 = 4;
         loop {
-            // defmt::trace!("poll");
-            while usb_dev.poll(&mut [&mut midi]) {}
+            let configured = ctx
+                .shared
+                .usb_dev
+                .lock(|usb_dev| usb_dev.state() == UsbDeviceState::Configured);
+
+            // pressing again while learning is ignored, so the button needs no debouncing
+            if ctx.local.learn_button.is_low() {
+                ctx.shared.learn.lock(|learn| {
+                    if !learn.is_active() {
+                        defmt::info!("learn, move a control");
+                        learn.start();
+                    }
+                });
+            }
 
-            if usb_dev.state() == UsbDeviceState::Configured {
-                let mut data_acc: u32 = 0;
+            if configured {
+                let settings = ctx.shared.settings.lock(|settings| *settings);
+                let calibrating = ctx
+                    .shared
+                    .calibration
+                    .lock(|calibration| calibration.is_active());
+
+                let (samples, dma) = adc_dma.read(buf).wait();
+                adc_dma = dma;
+
+                for (i, (pot, sample)) in pots.iter_mut().zip(samples.iter()).enumerate() {
+                    let control = settings.controls[i];
+                    let channel = settings.control_channel(i);
+
+                    // spikes and adc noise are filtered before the curve, jitter after it
+                    let raw = pot.average.update(pot.median.update(*sample));
+                    if calibrating {
+                        ctx.shared
+                            .calibration
+                            .lock(|calibration| calibration.feed(i, raw));
+                        continue;
+                    }
+                    pot.hysteresis.set_band(control.threshold);
+                    let value = match pot.hysteresis.update(to_value(settings.response(i, raw))) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    defmt::debug!("control {}, value {}", i, value);
+
+                    // while learning, moving a control selects it instead of sending
+                    let learning = ctx.shared.learn.lock(|learn| {
+                        learn.control_moved(i);
+                        learn.is_active()
+                    });
+                    if learning {
+                        continue;
+                    }
 
-                // take a sequence of samples and compute the average (adc noise)
-                for _ in 0..NR_SAMPLES {
-                    let sample: u16 = adc1.read(&mut ch0).unwrap();
-                    data_acc += sample as u32;
+                    // the pot position is tracked also when pickup mode is off
+                    let picked = ctx.shared.pickup.lock(|pickup| pickup.update(i, value));
+                    if settings.pickup && !picked {
+                        continue;
+                    }
+
+                    ctx.shared.midi.lock(|midi| {
+                        let res = if HIGH_RES {
+                            midi.ctrl_14bit(channel, control.cc, value)
+                        } else {
+                            midi.ctrl(channel, control.cc, value as u8)
+                        };
+                        if res.is_err() {
+                            defmt::info!("queue full, dropped {}", midi.dropped());
+                        }
+                        defmt::trace!("queued {}", midi.queue_len());
+                    });
                 }
-                let data_raw = data_acc / NR_SAMPLES;
+                buf = samples;
 
-                let data_msb: u8 = (data_raw >> 5) as u8;
+                waiting = settings.pickup && ctx.shared.pickup.lock(|pickup| pickup.is_waiting());
+            }
 
-                // defmt::trace!("raw: {}, msb {}", data_raw, data_msb);
+            // the LED is only driven here, so no indication turns off another: it stays
+            // on while learning or calibrating, then while a pot waits for pickup, then
+            // while a note is held on the host
+            let busy = ctx.shared.learn.lock(|learn| learn.is_active())
+                || ctx
+                    .shared
+                    .calibration
+                    .lock(|calibration| calibration.is_active());
+            let note_on = ctx.shared.note_on.lock(|note_on| *note_on);
+            if busy || waiting || note_on {
+                ctx.local.led.set_low();
+            } else {
+                ctx.local.led.set_high();
+            }
+        }
+    }
 
-                // we initiate `send` mode only if new data differs by 2
-                // we stay in `send` = true as long as new data differs
-                let diff: i8 = old_data_msb as i8 - data_msb as i8;
-                if diff.abs() > 1 || send {
-                    defmt::debug!("old msb {}, msb {}", old_data_msb, data_msb);
-                    send = old_data_msb != data_msb;
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi, sysex_rx, reply], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        let shared = ctx.shared;
+        (shared.usb_dev, shared.midi, shared.sysex_rx, shared.reply).lock(usb_poll);
+    }
 
-                    old_data_msb = data_msb;
-                    match midi.ctrl(0, 1, data_msb) {
-                        Ok(_) => {}
-                        Err(UsbError::BufferOverflow) => {
-                            defmt::info!("overflow");
-                        }
-                        Err(UsbError::WouldBlock) => {
-                            // skipping
-                            defmt::info!("busy");
-                        }
-                        _ => {
-                            defmt::info!("other error");
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi, sysex_rx, reply], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        let shared = ctx.shared;
+        (shared.usb_dev, shared.midi, shared.sysex_rx, shared.reply).lock(usb_poll);
+    }
+
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+        sysex_rx: &mut SysExReceiver<{ protocol::MAX_REQUEST_LEN }>,
+        reply: &mut Option<Vec<u8, { protocol::MAX_REPLY_LEN }>>,
+    ) {
+        if !usb_dev.poll(&mut [midi]) {
+            return;
+        }
+
+        // a completed transfer made room in the queue
+        send_reply(midi, reply);
+
+        match midi.poll_events() {
+            Ok(packets) => {
+                for packet in packets {
+                    if let Some(msg) = packet.message() {
+                        if on_midi::spawn(msg).is_err() {
+                            defmt::info!("dropped {}", msg);
                         }
+                    } else if let Ok(Some(data)) = sysex_rx.push(&packet) {
+                        on_sysex::spawn(Vec::from_slice(data).unwrap()).ok();
                     }
                 }
             }
+            Err(_) => defmt::info!("read error"),
+        }
+    }
+
+    // the longest reply fits in the empty queue
+    const _: () = assert!(protocol::MAX_REPLY_LEN <= midi::TX_QUEUE_LEN * 3);
+
+    // Queues the pending SysEx reply once the queue has room for all of it
+    //
+    // A dump is 44 packets, sending it in parts would interleave it with the CCs of
+    // the pots.
+    fn send_reply(
+        midi: &mut MidiClass<'static, UsbBusType>,
+        reply: &mut Option<Vec<u8, { protocol::MAX_REPLY_LEN }>>,
+    ) {
+        let fits = match reply {
+            Some(data) => data.len() <= (midi::TX_QUEUE_LEN - midi.queue_len()) * 3,
+            None => false,
+        };
+        if fits {
+            let data = reply.take().unwrap();
+            // all of it is queued, an error is only about starting the transfer
+            if midi.send_sysex(0, &data).is_err() {
+                defmt::info!("write error");
+            }
+        }
+    }
+
+    // SysEx messages received from the host, settings requests are answered and
+    // changes stored in flash
+    #[task(shared = [midi, reply, settings, learn, calibration], priority = 1, capacity = 2)]
+    fn on_sysex(mut ctx: on_sysex::Context, msg: Vec<u8, { protocol::MAX_REQUEST_LEN }>) {
+        let response = match ctx
+            .shared
+            .settings
+            .lock(|settings| protocol::handle(settings, DEVICE_ID, MAX_CC, &msg))
+        {
+            Some(response) => response,
+            None => return,
+        };
+        (&mut ctx.shared.midi, &mut ctx.shared.reply).lock(|midi, reply| {
+            if reply.is_some() {
+                defmt::info!("dropped the previous reply, not sent yet");
+            }
+            *reply = Some(response.reply);
+            send_reply(midi, reply);
+        });
+
+        if let Some(start) = response.learn {
+            ctx.shared.learn.lock(|learn| {
+                if start {
+                    defmt::info!("learn, move a control");
+                    learn.start();
+                } else {
+                    learn.cancel();
+                }
+            });
+        }
+        let mut changed = response.changed;
+        if let Some(start) = response.calibrate {
+            (ctx.shared.settings, ctx.shared.calibration).lock(|settings, calibration| {
+                if start {
+                    defmt::info!("calibrating, turn every pot from end to end");
+                    calibration.start();
+                } else {
+                    let calibrated = calibration.finish(settings);
+                    defmt::info!("calibrated {} controls", calibrated);
+                    changed |= calibrated > 0;
+                }
+            });
+        }
+        if changed {
+            store::spawn().ok();
+        }
+    }
+
+    // Stores the current settings in flash
+    #[task(shared = [settings], local = [flash], priority = 1)]
+    fn store(mut ctx: store::Context) {
+        let settings = ctx.shared.settings.lock(|settings| *settings);
+        defmt::info!("storing {}", settings);
+        if settings::flash::store(ctx.local.flash, &settings).is_err() {
+            defmt::info!("storing the settings failed");
+        }
+    }
+
+    // Messages received from the host, a CC while learning binds the selected control
+    #[task(shared = [settings, learn, pickup, note_on], local = [params: ParameterTracker = ParameterTracker::new()], priority = 1, capacity = 8)]
+    fn on_midi(mut ctx: on_midi::Context, msg: MidiMessage) {
+        defmt::debug!("received {}", msg);
+        if let Some(change) = ctx.local.params.feed(&msg) {
+            defmt::info!("parameter {}", change);
+        }
+
+        match msg {
+            MidiMessage::NoteOn { velocity, .. } if velocity > 0 => {
+                ctx.shared.note_on.lock(|note_on| *note_on = true)
+            }
+            MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => {
+                ctx.shared.note_on.lock(|note_on| *note_on = false)
+            }
+            _ => {}
+        }
+
+        let learned = (&mut ctx.shared.settings, &mut ctx.shared.learn).lock(|settings, learn| {
+            match learn.feed(settings, &msg) {
+                Some(index) => {
+                    defmt::info!("learned control {}: {}", index, settings.controls[index]);
+                    true
+                }
+                None => false,
+            }
+        });
+        if learned {
+            store::spawn().ok();
+            return;
         }
 
-        (Shared {}, Local {}, init::Monotonics())
+        if let MidiMessage::ControlChange {
+            channel,
+            control,
+            value,
+        } = msg
+        {
+            (ctx.shared.settings, ctx.shared.pickup).lock(|settings, pickup| {
+                if settings.pickup {
+                    track_host_value(settings, pickup, channel, control, value);
+                }
+            });
+        }
+    }
+
+    // Sets the value on the host of the pots bound to a received CC
+    fn track_host_value(
+        settings: &Settings,
+        pickup: &mut Pickup<NR_CONTROLS>,
+        channel: u8,
+        cc: u8,
+        value: u8,
+    ) {
+        for (i, control) in settings.controls[..NR_CONTROLS].iter().enumerate() {
+            if settings.control_channel(i) != channel {
+                continue;
+            }
+            if cc == control.cc {
+                let value = if HIGH_RES {
+                    (value as u16) << 7
+                } else {
+                    value as u16
+                };
+                pickup.set_host_value(i, value);
+            } else if HIGH_RES && cc == control.cc + 32 {
+                // the LSB of a 14 bit value, after the MSB
+                let msb = pickup.host_value(i).unwrap_or(0) & !0x7f;
+                pickup.set_host_value(i, msb | value as u16);
+            }
+        }
     }
 }
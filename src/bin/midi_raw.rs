@@ -1,121 +1,78 @@
 // DEFMT_LOG=info cargo rrb midi_raw
+//
+// Plays a step sequencer pattern on channel 0 while the host is listening. The
+// on-board LED is on while a note sounds.
+//
+// The sequencer is polled by a task scheduled on a TIM2 based 1 MHz monotonic, at the
+// time it is due next. Notes are ended when the host goes away.
 
 #![no_std]
 #![no_main]
 
 use f103_rtic as _; // global logger + panicking-behavior + memory layout
 
-mod midi {
-    use usb_device::class_prelude::*;
-    use usb_device::Result;
-
-    pub const USB_CLASS_AUDIO: u8 = 0x01;
-
-    pub struct MidiClass<'a, B: UsbBus> {
-        audio_if: InterfaceNumber,
-        midi_if: InterfaceNumber,
-        out_ep: EndpointOut<'a, B>,
-        in_ep: EndpointIn<'a, B>,
-    }
-
-    impl<B: UsbBus> MidiClass<'_, B> {
-        pub fn new(alloc: &UsbBusAllocator<B>) -> MidiClass<'_, B> {
-            MidiClass {
-                audio_if: alloc.interface(),
-                midi_if: alloc.interface(),
-                out_ep: alloc.bulk(64),
-                in_ep: alloc.bulk(64),
-            }
-        }
-
-        pub fn note_off(&self, chan: u8, key: u8, vel: u8) -> Result<usize> {
-            // I have no idea why the "virtual cable" must be number 0 and not one of the jack IDs
-            // but only 0 seemed to work
-            self.in_ep
-                .write(&[0x08, 0x80 | (chan & 0x0f), key & 0x7f, vel & 0x7f])
-        }
-
-        pub fn note_on(&self, chan: u8, key: u8, vel: u8) -> Result<usize> {
-            self.in_ep
-                .write(&[0x09, 0x90 | (chan & 0x0f), key & 0x7f, vel & 0x7f])
-        }
-    }
-
-    impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
-        fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-            writer.interface(self.audio_if, 0x01, 0x01, 0x00)?; // Interface 0
-            writer.write(
-                0x24, /* interface decscriptor */
-                &[0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01],
-            )?; // CS Interface (audio)
-
-            writer.interface(self.midi_if, 0x01, 0x03, 0x00)?; // Interface 1
-            writer.write(0x24, &[0x01, 0x00, 0x01, 0x2e, 0x00])?; // CS Interface (midi)
-
-            writer.write(
-                0x24, /* Descriptor */
-                &[
-                    0x02, /* midi jack in */
-                    0x01, /* embedded */
-                    0x01, /* id */
-                    0x00, /* unused */
-                ],
-            )?; // IN Jack 1 (emb)
-            writer.write(
-                0x24, /* Descriptor */
-                &[
-                    0x03, /* midi jack out */
-                    // 0x01 /* embedded */,
-                    0x02, /* external */
-                    0x02, /* id */
-                    // 0x01 /* nr of input pins */,
-                    0x00, /* nr of input pins */
-                    // 0x01 /* id of entity this this pin is connected to */,
-                    // 0x01 /* output pin number number of the entitiy to which this pin is connected */,
-                    0x00,
-                ], /* unsused */
-            )?; // OUT Jack 2 (emb)
-
-            writer.endpoint(&self.out_ep)?;
-            writer.write(0x25, &[0x01, 0x01, 0x01])?; // CS EP IN Jack
-
-            writer.endpoint(&self.in_ep)?;
-            writer.write(0x25, &[0x01, 0x01, 0x02])?; // CS EP OUT Jack
-
-            Ok(())
-        }
-    }
-}
-
 #[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
 mod app {
     use cortex_m::asm::delay;
-    // use nb::block;
-    //  use stm32f103xx_usb::UsbBus;
-    use super::midi;
+    use f103_rtic::midi::{
+        self,
+        sequencer::{Sequencer, Step},
+        MidiClass, MidiMessage,
+    };
+    use fugit::ExtU32;
     use stm32f1xx_hal::{
-        adc,
+        gpio::{gpioc::PC13, Output, PushPull},
         pac,
         prelude::*,
-        usb::{Peripheral, UsbBus},
-        // dma::{
-        //     dma1::{C4, C5},
-        //     Event, RxDma, Transfer, TxDma, R, W,
-        // },
-        // pac::USART1,
-        // prelude::*,
-        // serial::{Config, Rx, Serial, Tx}
+        timer::{MonoTimer, MonoTimerExt},
+        usb::{Peripheral, UsbBus, UsbBusType},
     };
-    use stm32f1xx_hal::{prelude::*, stm32, timer::Timer};
-    use usb_device::prelude::*;
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+
+    const BPM: u32 = 100;
+
+    // percent, 50 for straight sixteenths
+    const SWING: u8 = 58;
+
+    // a C major run, with accents, a tie and some ghost notes
+    const PATTERN: &[Step] = &[
+        Step::note(60, 100),
+        Step::note(62, 64),
+        Step::note(64, 80),
+        Step::note(65, 40).probability(50),
+        Step::note(67, 100).gate(180),
+        Step::REST,
+        Step::note(69, 80),
+        Step::note(71, 40).probability(50),
+        Step::note(72, 110).gate(90),
+        Step::note(71, 64),
+        Step::note(69, 64),
+        Step::note(67, 40).probability(25),
+        Step::note(65, 80).gate(20),
+        Step::note(64, 80).gate(20),
+        Step::note(62, 80).gate(20),
+        Step::REST,
+    ];
+
+    // how often the task checks the USB state while stopped
+    const IDLE_US: u32 = 10_000;
+
+    #[monotonic(binds = TIM2, default = true)]
+    type MicrosMono = MonoTimer<pac::TIM2, 1_000_000>;
 
     #[shared]
-    struct Shared {}
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+    }
 
     #[local]
-    struct Local {}
+    struct Local {
+        led: PC13<Output<PushPull>>,
+        seq: Sequencer,
+    }
 
-    #[init()]
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("init");
 
@@ -133,12 +90,14 @@ mod app {
 
         assert!(clocks.usbclk_valid(), "usb clocks not valid");
 
+        let mono = p.TIM2.monotonic_us(&clocks);
+
         let mut gpioa = p.GPIOA.split();
         let mut gpioc = p.GPIOC.split();
 
         // Configure the on-board LED (PC13, green)
         let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
-        led.set_low(); // Turn on
+        led.set_high(); // Turn off
 
         // BluePill board has a pull-up resistor on the D+ line.
         // Pull the D+ pin down to send a RESET condition to the USB bus.
@@ -154,53 +113,90 @@ mod app {
             pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
         };
 
-        let usb_bus = UsbBus::new(usb);
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
 
-        let mut midi = midi::MidiClass::new(&usb_bus);
+        let midi = MidiClass::new(usb_bus);
 
-        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27de))
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
             .manufacturer("Fake company")
             .product("MIDI Device")
             .serial_number("TEST")
             .device_class(midi::USB_CLASS_AUDIO)
             .build();
 
-        // usb_dev.force_reset().expect("reset failed");
-
-        // let mut timer = Timer::syst(cp.SYST, 1000.hz(), clocks);
-
-        let notes = &[60, 62, 64, 65, 67, 69, 71, 72];
-        let mut note_iter = notes.iter().cycle();
-        let mut note = *note_iter.next().unwrap();
-
-        let mut ms = 0;
-        loop {
-            // defmt::trace!("poll");
-            while usb_dev.poll(&mut [&mut midi]) {}
-
-            if usb_dev.state() == UsbDeviceState::Configured {
-                // Excuse the super crude sequencer
-
-                if ms == 200 {
-                    if midi.note_on(0, note, 64).is_ok() {
-                        defmt::trace!("note on");
-                        led.set_low();
-                        ms += 1;
-                    }
-                } else if ms == 400 {
-                    if midi.note_off(0, note, 0).is_ok() {
-                        defmt::trace!("note off");
-                        led.set_high();
-                        ms = 0;
-                        note = *note_iter.next().unwrap();
-                    }
-                } else {
-                    ms += 1;
+        let mut seq = Sequencer::new(0, BPM);
+        seq.set_swing(SWING);
+        seq.set_pattern(PATTERN);
+
+        play::spawn().ok();
+
+        (
+            Shared { usb_dev, midi },
+            Local { led, seq },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Sends the messages that are due, and schedules itself for the next ones
+    #[task(shared = [usb_dev, midi], local = [led, seq])]
+    fn play(ctx: play::Context) {
+        let seq = ctx.local.seq;
+        let led = ctx.local.led;
+        let now = monotonics::now().ticks();
+
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(|usb_dev, midi| {
+            let configured = usb_dev.state() == UsbDeviceState::Configured;
+            if configured && !seq.is_running() {
+                defmt::info!("start");
+                seq.start(now);
+            } else if !configured && seq.is_running() {
+                defmt::info!("stop");
+                seq.stop();
+            }
+
+            while let Some(msg) = seq.poll(now) {
+                match msg {
+                    MidiMessage::NoteOn { .. } => led.set_low(),
+                    _ => led.set_high(),
+                }
+                defmt::trace!("{}", msg);
+                if configured && midi.send(msg).is_err() {
+                    defmt::info!("dropped {}", msg);
                 }
-                delay(100_000);
             }
+        });
+
+        match seq.next_due(now) {
+            Some(at) => play::spawn_at(fugit::TimerInstantU32::from_ticks(at)),
+            None => play::spawn_after(IDLE_US.micros()),
         }
+        .ok();
+    }
+
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi).lock(usb_poll);
+    }
 
-        (Shared {}, Local {}, init::Monotonics())
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+    ) {
+        if !usb_dev.poll(&mut [midi]) {
+            return;
+        }
+
+        // nothing to do with messages from the host, just keep the endpoint going
+        if let Ok(packets) = midi.poll_events() {
+            for packet in packets {
+                defmt::debug!("ignored {}", packet);
+            }
+        }
     }
 }
@@ -0,0 +1,202 @@
+// DEFMT_LOG=info cargo rrb midi_sync
+//
+// Follows a MIDI clock received over USB or DIN MIDI on USART1 (RX PA10), and logs
+// transport changes, the position and the estimated tempo on every beat. The on-board
+// LED flashes on the beats while playing.
+//
+// Messages are timestamped on arrival with a TIM2 based 1 MHz monotonic. The follower
+// is a shared resource, so any task can lock it and sync to the current position.
+
+#![no_std]
+#![no_main]
+
+use f103_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
+mod app {
+    use cortex_m::asm::delay;
+    use f103_rtic::midi::{
+        self,
+        clock::{ClockEvent, ClockFollower, PPQN},
+        din,
+        din::DinRx,
+        MidiClass, MidiMessage,
+    };
+    use stm32f1xx_hal::{
+        gpio::{gpioc::PC13, Output, PushPull},
+        pac::{self, USART1},
+        prelude::*,
+        serial::{Config, Event::Rxne, Rx, Serial},
+        timer::{MonoTimer, MonoTimerExt},
+        usb::{Peripheral, UsbBus, UsbBusType},
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+
+    #[monotonic(binds = TIM2, default = true)]
+    type MicrosMono = MonoTimer<pac::TIM2, 1_000_000>;
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+        follower: ClockFollower,
+    }
+
+    #[local]
+    struct Local {
+        din_rx: DinRx<Rx<USART1>>,
+        led: PC13<Output<PushPull>>,
+    }
+
+    #[init(local = [usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        let p = ctx.device;
+
+        let rcc = p.RCC.constrain();
+        let mut flash = p.FLASH.constrain();
+
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .freeze(&mut flash.acr);
+
+        assert!(clocks.usbclk_valid(), "usb clocks not valid");
+
+        let mono = p.TIM2.monotonic_us(&clocks);
+
+        let mut afio = p.AFIO.constrain();
+        let mut gpioa = p.GPIOA.split();
+        let mut gpioc = p.GPIOC.split();
+
+        // Configure the on-board LED (PC13, green)
+        let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
+        led.set_high(); // Turn off
+
+        // Setup DIN MIDI
+        let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
+        let rx = gpioa.pa10;
+        let mut serial = Serial::usart1(
+            p.USART1,
+            (tx, rx),
+            &mut afio.mapr,
+            Config::default().baudrate(din::BAUD_RATE.bps()),
+            clocks,
+        );
+        serial.listen(Rxne);
+        let (_, rx) = serial.split();
+
+        // Setup USB
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb = Peripheral {
+            usb: p.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
+
+        let midi = MidiClass::new(usb_bus);
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
+            .manufacturer("Fake company")
+            .product("MIDI Sync")
+            .serial_number("TEST")
+            .device_class(midi::USB_CLASS_AUDIO)
+            .build();
+
+        (
+            Shared {
+                usb_dev,
+                midi,
+                follower: ClockFollower::new(),
+            },
+            Local {
+                din_rx: DinRx::new(rx),
+                led,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi, follower], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi, ctx.shared.follower).lock(usb_poll);
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi, follower], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        (ctx.shared.usb_dev, ctx.shared.midi, ctx.shared.follower).lock(usb_poll);
+    }
+
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+        follower: &mut ClockFollower,
+    ) {
+        if !usb_dev.poll(&mut [midi]) {
+            return;
+        }
+
+        match midi.poll_events() {
+            Ok(packets) => {
+                for msg in packets.filter_map(|packet| packet.message()) {
+                    follow(follower, &msg);
+                }
+            }
+            Err(_) => defmt::info!("read error"),
+        }
+    }
+
+    // Triggers on DIN RX not empty
+    #[task(binds = USART1, shared = [follower], local = [din_rx], priority = 2)]
+    fn on_din(mut ctx: on_din::Context) {
+        while let Ok(msg) = ctx.local.din_rx.read() {
+            ctx.shared.follower.lock(|follower| follow(follower, &msg));
+        }
+    }
+
+    // Feeds a received message to the follower, timestamped now
+    fn follow(follower: &mut ClockFollower, msg: &MidiMessage) {
+        let now = monotonics::now().ticks();
+        if let Some(event) = follower.feed(msg, now) {
+            if on_clock::spawn(event).is_err() {
+                defmt::info!("dropped {}", event);
+            }
+        }
+    }
+
+    #[task(shared = [follower], local = [led], priority = 1, capacity = 8)]
+    fn on_clock(mut ctx: on_clock::Context, event: ClockEvent) {
+        let led = ctx.local.led;
+        match event {
+            ClockEvent::Tick(position) => match position.tick() {
+                0 => {
+                    led.set_low();
+                    let bpm = ctx.shared.follower.lock(|follower| follower.bpm());
+                    defmt::info!("beat {}, {} bpm", position.beat(), bpm);
+                }
+                // flash the LED for the first 1/8 of every beat
+                tick if tick == PPQN / 8 => led.set_high(),
+                _ => {}
+            },
+            ClockEvent::Stop(_) => {
+                led.set_high();
+                defmt::info!("{}", event);
+            }
+            _ => defmt::info!("{}", event),
+        }
+    }
+}
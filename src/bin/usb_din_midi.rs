@@ -0,0 +1,251 @@
+// DEFMT_LOG=info cargo rrb usb_din_midi
+//
+// USB MIDI interface, bridging the USB MIDI port and DIN MIDI on USART1
+// (TX PA9, RX PA10) in both directions, including SysEx and real-time messages.
+//
+// DIN input is received using DMA, as in `serial_circ_idle`. USB output to DIN is
+// paced by the UART: while there is no room for a full transfer, the OUT endpoint is
+// not read, so the host is NAKed, the rest of USB keeps going. DIN input the USB side
+// does not keep up with is dropped and logged.
+#![no_main]
+#![no_std]
+
+use f103_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI1])]
+mod app {
+    use cortex_m::asm::delay;
+    use f103_rtic::midi::{self, din, din::DinTx, parser::PacketParser, MidiClass};
+    use heapless::Vec;
+    use stm32f1xx_hal::{
+        dma::{dma1::C5, CircBuffer, Event, Half, RxDma},
+        pac::{self, USART1},
+        prelude::*,
+        serial::{Config, Event::Idle, Rx, Serial, Tx},
+        usb::{Peripheral, UsbBus, UsbBusType},
+    };
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+
+    // half of the DMA buffer, at 31250 baud a half fills in 2.5 ms
+    const BUF_SIZE: usize = 8;
+
+    #[shared]
+    struct Shared {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        midi: MidiClass<'static, UsbBusType>,
+        din_tx: DinTx<Tx<USART1>, 64>,
+        // a transfer from the host waits on the OUT endpoint
+        out_pending: bool,
+        #[lock_free]
+        recv: Option<CircBuffer<[u8; BUF_SIZE], RxDma<Rx<USART1>, C5>>>,
+    }
+
+    #[local]
+    struct Local {}
+
+    #[init(local = [
+        rx_buf: [[u8; BUF_SIZE]; 2] = [[0; BUF_SIZE]; 2],
+        usb_bus: Option<UsbBusAllocator<UsbBusType>> = None,
+    ])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        defmt::info!("init");
+
+        let p = ctx.device;
+
+        let rcc = p.RCC.constrain();
+        let mut flash = p.FLASH.constrain();
+
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .freeze(&mut flash.acr);
+
+        assert!(clocks.usbclk_valid(), "usb clocks not valid");
+
+        let mut afio = p.AFIO.constrain();
+        let mut gpioa = p.GPIOA.split();
+
+        // Setup DIN MIDI, receiving with DMA into a circular buffer and on line idle,
+        // the same setup as in `serial_circ_idle`
+        let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
+        let rx = gpioa.pa10;
+        let mut serial = Serial::usart1(
+            p.USART1,
+            (tx, rx),
+            &mut afio.mapr,
+            Config::default().baudrate(din::BAUD_RATE.bps()),
+            clocks,
+        );
+        serial.listen(Idle);
+        let mut channels = p.DMA1.split();
+        channels.5.listen(Event::HalfTransfer);
+        channels.5.listen(Event::TransferComplete);
+        let (tx_serial, rx_serial) = serial.split();
+        let rx = rx_serial.with_dma(channels.5);
+
+        // Setup USB
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb = Peripheral {
+            usb: p.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+
+        *ctx.local.usb_bus = Some(UsbBus::new(usb));
+        let usb_bus = ctx.local.usb_bus.as_ref().unwrap();
+
+        let midi = MidiClass::with_ports(usb_bus, &["DIN"]);
+
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27de))
+            .manufacturer("Fake company")
+            .product("USB DIN MIDI")
+            .serial_number("TEST")
+            .device_class(midi::USB_CLASS_AUDIO)
+            .build();
+
+        (
+            Shared {
+                usb_dev,
+                midi,
+                din_tx: DinTx::new(tx_serial),
+                out_pending: false,
+                recv: Some(rx.circ_read(ctx.local.rx_buf)),
+            },
+            Local {},
+            init::Monotonics(),
+        )
+    }
+
+    // Drains the DIN transmit queue, resumes reading from USB once there is room again
+    #[idle(shared = [din_tx, out_pending])]
+    fn idle(mut ctx: idle::Context) -> ! {
+        loop {
+            let resume = (&mut ctx.shared.din_tx, &mut ctx.shared.out_pending).lock(
+                |din_tx, out_pending| {
+                    din_tx.flush().ok();
+                    *out_pending && din_tx.has_room(midi::PACKETS_PER_TRANSFER)
+                },
+            );
+            if resume {
+                rtic::pend(pac::Interrupt::USB_LP_CAN_RX0);
+            }
+        }
+    }
+
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_dev, midi, din_tx, out_pending], priority = 2)]
+    fn usb_tx(ctx: usb_tx::Context) {
+        let shared = ctx.shared;
+        (
+            shared.usb_dev,
+            shared.midi,
+            shared.din_tx,
+            shared.out_pending,
+        )
+            .lock(usb_poll);
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, midi, din_tx, out_pending], priority = 2)]
+    fn usb_rx0(ctx: usb_rx0::Context) {
+        let shared = ctx.shared;
+        (
+            shared.usb_dev,
+            shared.midi,
+            shared.din_tx,
+            shared.out_pending,
+        )
+            .lock(usb_poll);
+    }
+
+    fn usb_poll(
+        usb_dev: &mut UsbDevice<'static, UsbBusType>,
+        midi: &mut MidiClass<'static, UsbBusType>,
+        din_tx: &mut DinTx<Tx<USART1>, 64>,
+        out_pending: &mut bool,
+    ) {
+        if usb_dev.poll(&mut [midi]) {
+            *out_pending = true;
+        }
+        if !*out_pending {
+            return;
+        }
+        if !din_tx.has_room(midi::PACKETS_PER_TRANSFER) {
+            // the OUT endpoint NAKs until read, `idle` pends this again once there is room
+            return;
+        }
+        *out_pending = false;
+
+        match midi.poll_events() {
+            Ok(packets) => {
+                for packet in packets {
+                    if din_tx.send_packet(&packet).is_err() {
+                        defmt::info!("din dropped {}", packet);
+                    }
+                }
+            }
+            Err(_) => defmt::info!("read error"),
+        }
+    }
+
+    // Triggers on RX half transfer or transfer completed, as in `serial_circ_idle`
+    #[task(binds = DMA1_CHANNEL5, shared = [recv], priority = 2)]
+    fn on_rx(ctx: on_rx::Context) {
+        let rx = ctx.shared.recv.as_mut().unwrap();
+        let buf = rx.peek(|buf, _| *buf).unwrap();
+        forward(&buf);
+    }
+
+    // Triggers on serial line Idle, as in `serial_circ_idle`
+    #[task(binds = USART1, shared = [recv], priority = 2)]
+    fn on_idle(ctx: on_idle::Context) {
+        clear_idle_interrupt();
+        let mut recv = ctx.shared.recv.take().unwrap();
+        let inactive_half = recv.readable_half().unwrap();
+        let (buf, rx) = recv.stop();
+        let pending = rx.channel.get_ndtr() as usize;
+        let data = match inactive_half {
+            Half::First => &buf[1][..BUF_SIZE - pending],
+            Half::Second => &buf[0][..2 * BUF_SIZE - pending],
+        };
+        forward(data);
+        ctx.shared.recv.replace(rx.circ_read(buf));
+    }
+
+    // Passes received bytes on to `din_in`, they are lost if it is behind by the
+    // capacity of the task, 8 chunks or 20 ms of input at full speed
+    fn forward(data: &[u8]) {
+        if din_in::spawn(Vec::from_slice(data).unwrap()).is_err() {
+            defmt::info!("din in dropped {} bytes", data.len());
+        }
+    }
+
+    // DIN input to USB
+    #[task(shared = [midi], local = [parser: PacketParser = PacketParser::new(0)], priority = 1, capacity = 8)]
+    fn din_in(mut ctx: din_in::Context, data: Vec<u8, BUF_SIZE>) {
+        let parser = ctx.local.parser;
+        ctx.shared.midi.lock(|midi| {
+            for packet in data.iter().flat_map(|byte| parser.feed(*byte)) {
+                if midi.send_packet(packet).is_err() {
+                    defmt::info!("usb dropped {}", packet);
+                }
+            }
+        });
+    }
+
+    #[inline]
+    fn clear_idle_interrupt() {
+        unsafe {
+            let _ = (*USART1::ptr()).sr.read().idle();
+            let _ = (*USART1::ptr()).dr.read().bits();
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Push buttons
+//!
+//! `Button` debounces the state of a button, read from a GPIO at regular intervals (a
+//! timer scan) or on EXTI edges, and detects short, long and double presses. What a
+//! button sends is set by its `Mapping`, with an `Action` for a press, a long press and
+//! a double press.
+//!
+//! Like the sequencer, buttons keep time in µs that wrap around. Call `update` with
+//! the current state of the button and the time, every few ms.
+
+use crate::midi::MidiMessage;
+use heapless::Vec;
+
+/// Timing of the gestures, in µs
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Timing {
+    /// Time the button must be stable to take its state
+    pub debounce: u32,
+    /// Time held for a long press
+    pub long: u32,
+    /// Time between the release and the next press for a double press
+    ///
+    /// Short presses are reported after this time, or right away when the button has
+    /// no double press action.
+    pub double: u32,
+}
+
+impl Default for Timing {
+    /// 10 ms debounce, 500 ms long and 300 ms double press
+    fn default() -> Self {
+        Timing {
+            debounce: 10_000,
+            long: 500_000,
+            double: 300_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ButtonEvent {
+    Pressed,
+    Released,
+    /// Released before a long press, and not pressed again for a double press
+    Short,
+    /// Held for `Timing::long`, while still held
+    Long,
+    /// Pressed again shortly after a short press, instead of `Pressed`
+    Double,
+}
+
+/// What a button sends
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Action {
+    /// Note on, and note off on release
+    Note {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    /// CC 127, and 0 on release
+    MomentaryCc {
+        channel: u8,
+        cc: u8,
+    },
+    /// CC 127 and 0 in turns
+    ToggleCc {
+        channel: u8,
+        cc: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+}
+
+impl Action {
+    // whether the action lasts until the button is released
+    fn is_held(&self) -> bool {
+        matches!(self, Action::Note { .. } | Action::MomentaryCc { .. })
+    }
+}
+
+/// Actions of the gestures of a button
+///
+/// The press action of a toggle or program change is sent on a short press when there
+/// is a long or double press action, so those do not send it as well. Notes and
+/// momentary CCs always follow the button.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, defmt::Format)]
+pub struct Mapping {
+    pub press: Option<Action>,
+    pub long: Option<Action>,
+    pub double: Option<Action>,
+}
+
+// index of the toggle state of each gesture
+const PRESS: usize = 0;
+const LONG: usize = 1;
+const DOUBLE: usize = 2;
+
+pub struct Button {
+    mapping: Mapping,
+    timing: Timing,
+    // the last reading and since when
+    raw: bool,
+    raw_since: u32,
+    // debounced state
+    pressed: bool,
+    pressed_at: u32,
+    long_sent: bool,
+    // the current press is the second of a double press
+    second: bool,
+    // released after a short press, waiting for a double press
+    released_at: Option<u32>,
+    toggled: [bool; 3],
+    // actions to end on release
+    held: Vec<Action, 3>,
+}
+
+impl Button {
+    pub fn new(mapping: Mapping) -> Self {
+        Button {
+            mapping,
+            timing: Timing::default(),
+            raw: false,
+            raw_since: 0,
+            pressed: false,
+            pressed_at: 0,
+            long_sent: false,
+            second: false,
+            released_at: None,
+            toggled: [false; 3],
+            held: Vec::new(),
+        }
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    pub fn set_mapping(&mut self, mapping: Mapping) {
+        self.mapping = mapping;
+    }
+
+    /// Whether the button is pressed, debounced
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Feeds the state of the button at `now`, returns the event it caused
+    ///
+    /// At most one event is returned per call, the others follow on the next calls.
+    pub fn update(&mut self, pressed: bool, now: u32) -> Option<ButtonEvent> {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_since = now;
+        }
+
+        if self.pressed
+            && !self.long_sent
+            && !self.second
+            && elapsed(self.pressed_at, now) >= self.timing.long
+        {
+            self.long_sent = true;
+            return Some(ButtonEvent::Long);
+        }
+        if let Some(at) = self.released_at {
+            let window = match self.mapping.double {
+                Some(_) => self.timing.double,
+                None => 0,
+            };
+            if elapsed(at, now) >= window {
+                self.released_at = None;
+                return Some(ButtonEvent::Short);
+            }
+        }
+
+        if self.raw == self.pressed || elapsed(self.raw_since, now) < self.timing.debounce {
+            return None;
+        }
+        self.pressed = self.raw;
+        if self.pressed {
+            self.pressed_at = now;
+            self.long_sent = false;
+            self.second = self.released_at.take().is_some();
+            Some(if self.second {
+                ButtonEvent::Double
+            } else {
+                ButtonEvent::Pressed
+            })
+        } else {
+            if !self.long_sent && !self.second {
+                self.released_at = Some(now);
+            }
+            self.second = false;
+            Some(ButtonEvent::Released)
+        }
+    }
+
+    /// Feeds the state of the button at `now`, returns the messages to send
+    pub fn poll(&mut self, pressed: bool, now: u32) -> Vec<MidiMessage, 3> {
+        let mut messages = Vec::new();
+        let event = match self.update(pressed, now) {
+            Some(event) => event,
+            None => return messages,
+        };
+
+        // a toggle or program change on press waits for the other gestures
+        let delayed = self.mapping.long.is_some() || self.mapping.double.is_some();
+        let (gesture, action) = match event {
+            ButtonEvent::Pressed => match self.mapping.press {
+                Some(action) if action.is_held() || !delayed => (PRESS, action),
+                _ => return messages,
+            },
+            ButtonEvent::Short => match self.mapping.press {
+                Some(action) if !action.is_held() && delayed => (PRESS, action),
+                _ => return messages,
+            },
+            ButtonEvent::Long => match self.mapping.long {
+                Some(action) => (LONG, action),
+                None => return messages,
+            },
+            ButtonEvent::Double => match self.mapping.double {
+                Some(action) => (DOUBLE, action),
+                None => return messages,
+            },
+            ButtonEvent::Released => {
+                while let Some(action) = self.held.pop() {
+                    messages.extend(off(action));
+                }
+                return messages;
+            }
+        };
+
+        let msg = match action {
+            Action::Note {
+                channel,
+                note,
+                velocity,
+            } => MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            },
+            Action::MomentaryCc { channel, cc } => cc_message(channel, cc, true),
+            Action::ToggleCc { channel, cc } => {
+                self.toggled[gesture] = !self.toggled[gesture];
+                cc_message(channel, cc, self.toggled[gesture])
+            }
+            Action::ProgramChange { channel, program } => {
+                MidiMessage::ProgramChange { channel, program }
+            }
+        };
+        if action.is_held() {
+            self.held.push(action).ok();
+        }
+        messages.push(msg).ok();
+        messages
+    }
+}
+
+// ends a held action
+fn off(action: Action) -> Option<MidiMessage> {
+    match action {
+        Action::Note { channel, note, .. } => Some(MidiMessage::note_off(channel, note)),
+        Action::MomentaryCc { channel, cc } => Some(cc_message(channel, cc, false)),
+        Action::ToggleCc { .. } | Action::ProgramChange { .. } => None,
+    }
+}
+
+fn cc_message(channel: u8, cc: u8, on: bool) -> MidiMessage {
+    MidiMessage::ControlChange {
+        channel,
+        control: cc,
+        value: if on { 127 } else { 0 },
+    }
+}
+
+fn elapsed(since: u32, now: u32) -> u32 {
+    now.wrapping_sub(since)
+}
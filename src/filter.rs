@@ -0,0 +1,336 @@
+//! Filters for noisy readings, e.g. of potentiometers
+//!
+//! `MovingAverage`, `Ema` and `Median` smooth a stream of samples, `Hysteresis` turns
+//! the smoothed stream into a stream of changes, ignoring the jitter left over. The
+//! filters are generic over the sample width (`u8`, `u16` or `u32`), sums are kept in
+//! 64 bits so they never overflow.
+//!
+//! A typical chain for a pot is a short `Median` against spikes, a `MovingAverage` or
+//! `Ema` against noise and a `Hysteresis` of about the noise left.
+
+/// Unsigned sample type
+pub trait Sample: Copy + Ord + Default {
+    fn to_u64(self) -> u64;
+    /// Converts back, `value` is always in range of the sample type
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_sample {
+    ($($t:ty),*) => {
+        $(
+            impl Sample for $t {
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_u64(value: u64) -> Self {
+                    value as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_sample!(u8, u16, u32);
+
+/// Filter of a stream of samples
+pub trait Filter<T: Sample> {
+    /// Feeds a sample, returns the filtered value
+    fn update(&mut self, sample: T) -> T;
+
+    /// Forgets the samples fed so far
+    fn reset(&mut self);
+}
+
+/// Average of the last `N` samples
+///
+/// Until `N` samples are fed, the average is over those fed so far.
+pub struct MovingAverage<T, const N: usize> {
+    samples: [T; N],
+    next: usize,
+    len: usize,
+    sum: u64,
+}
+
+impl<T: Sample, const N: usize> MovingAverage<T, N> {
+    // referenced in `new`, so `N = 0` fails to compile
+    const NOT_EMPTY: () = assert!(N > 0, "an average of no samples");
+
+    pub fn new() -> Self {
+        let () = Self::NOT_EMPTY;
+        MovingAverage {
+            samples: [T::default(); N],
+            next: 0,
+            len: 0,
+            sum: 0,
+        }
+    }
+}
+
+impl<T: Sample, const N: usize> Default for MovingAverage<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Sample, const N: usize> Filter<T> for MovingAverage<T, N> {
+    fn update(&mut self, sample: T) -> T {
+        if self.len == N {
+            self.sum -= self.samples[self.next].to_u64();
+        } else {
+            self.len += 1;
+        }
+        self.samples[self.next] = sample;
+        self.sum += sample.to_u64();
+        self.next = (self.next + 1) % N;
+
+        let len = self.len as u64;
+        T::from_u64((self.sum + len / 2) / len)
+    }
+
+    fn reset(&mut self) {
+        self.next = 0;
+        self.len = 0;
+        self.sum = 0;
+    }
+}
+
+/// Exponential moving average, each sample is weighted 1 / 2^`shift`
+///
+/// Cheaper than a `MovingAverage` of the same smoothing, but slower to settle after a
+/// step. The first sample is taken as is.
+pub struct Ema<T> {
+    shift: u32,
+    // the average, scaled by 2^shift
+    acc: Option<u64>,
+    _sample: core::marker::PhantomData<T>,
+}
+
+impl<T: Sample> Ema<T> {
+    /// The weight shift is clamped to `0..=16`
+    pub fn new(shift: u32) -> Self {
+        Ema {
+            shift: shift.min(16),
+            acc: None,
+            _sample: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: Sample> Filter<T> for Ema<T> {
+    fn update(&mut self, sample: T) -> T {
+        let x = sample.to_u64();
+        let acc = match self.acc {
+            Some(acc) => acc - (acc >> self.shift) + x,
+            None => x << self.shift,
+        };
+        self.acc = Some(acc);
+
+        let half = (1 << self.shift) >> 1;
+        T::from_u64((acc + half) >> self.shift)
+    }
+
+    fn reset(&mut self) {
+        self.acc = None;
+    }
+}
+
+/// Median of the last `N` samples, removes spikes shorter than half of `N`
+///
+/// `N` should be odd (3 or 5 is plenty), for an even number of samples the upper one
+/// of the middle two is taken.
+pub struct Median<T, const N: usize> {
+    samples: [T; N],
+    next: usize,
+    len: usize,
+}
+
+impl<T: Sample, const N: usize> Median<T, N> {
+    // referenced in `new`, so `N = 0` fails to compile
+    const NOT_EMPTY: () = assert!(N > 0, "a median of no samples");
+
+    pub fn new() -> Self {
+        let () = Self::NOT_EMPTY;
+        Median {
+            samples: [T::default(); N],
+            next: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<T: Sample, const N: usize> Default for Median<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Sample, const N: usize> Filter<T> for Median<T, N> {
+    fn update(&mut self, sample: T) -> T {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        // N is small, an insertion sort of a copy does
+        let mut sorted = self.samples;
+        let sorted = &mut sorted[..self.len];
+        for i in 1..sorted.len() {
+            let mut j = i;
+            while j > 0 && sorted[j - 1] > sorted[j] {
+                sorted.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+        sorted[sorted.len() / 2]
+    }
+
+    fn reset(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+}
+
+/// Hysteresis, the output follows the input only when it moves more than `band` away
+///
+/// The output takes the input as is, it only ignores changes: once moving, the output
+/// follows the input in the same direction step by step, while turning back takes a
+/// change of more than `band`. So jitter up to `band` around a resting input is
+/// ignored, and an input at rest reads its own value. Inputs at or beyond the limits,
+/// if set, are passed on as is.
+pub struct Hysteresis<T> {
+    band: u64,
+    limits: Option<(T, T)>,
+    value: Option<T>,
+    // direction of the last change
+    rising: Option<bool>,
+}
+
+impl<T: Sample> Hysteresis<T> {
+    pub fn new(band: T) -> Self {
+        Hysteresis {
+            band: band.to_u64(),
+            limits: None,
+            value: None,
+            rising: None,
+        }
+    }
+
+    /// Sets the ends of the input range
+    pub fn with_limits(mut self, min: T, max: T) -> Self {
+        self.limits = Some((min, max));
+        self
+    }
+
+    pub fn set_band(&mut self, band: T) {
+        self.band = band.to_u64();
+    }
+
+    /// The current output, `None` before the first sample
+    pub fn value(&self) -> Option<T> {
+        self.value
+    }
+
+    /// Feeds a sample, returns the new output if it changed
+    ///
+    /// The first sample is always a change.
+    pub fn update(&mut self, sample: T) -> Option<T> {
+        let new = match (self.value, self.limits) {
+            (_, Some((min, _))) if sample <= min => min,
+            (_, Some((_, max))) if sample >= max => max,
+            (None, _) => sample,
+            (Some(value), _) => {
+                let (x, y) = (sample.to_u64(), value.to_u64());
+                let (up, down) = match self.rising {
+                    Some(true) => (0, self.band),
+                    Some(false) => (self.band, 0),
+                    None => (self.band, self.band),
+                };
+                if x > y + up || x + down < y {
+                    sample
+                } else {
+                    value
+                }
+            }
+        };
+        match self.value {
+            Some(value) if value == new => return None,
+            Some(value) => self.rising = Some(new > value),
+            None => {}
+        }
+        self.value = Some(new);
+        Some(new)
+    }
+
+    /// Forgets the output, the next sample is a change
+    pub fn reset(&mut self) {
+        self.value = None;
+        self.rising = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_average() {
+        let mut average = MovingAverage::<u16, 4>::new();
+        assert_eq!(average.update(100), 100);
+        assert_eq!(average.update(200), 150);
+        for _ in 0..4 {
+            average.update(1000);
+        }
+        assert_eq!(average.update(1000), 1000);
+        average.reset();
+        assert_eq!(average.update(10), 10);
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let mut median = Median::<u8, 3>::new();
+        let out: [u8; 6] = [10, 11, 255, 12, 0, 13].map(|x| median.update(x));
+        assert_eq!(out, [10, 11, 11, 12, 12, 12]);
+    }
+
+    #[test]
+    fn hysteresis_reaches_the_input() {
+        let mut hysteresis = Hysteresis::new(1u8);
+        assert_eq!(hysteresis.update(64), Some(64));
+        // jitter at rest
+        assert_eq!(hysteresis.update(63), None);
+        assert_eq!(hysteresis.update(65), None);
+        assert_eq!(hysteresis.update(64), None);
+        // turned up to the end, every step is sent and the end is reached
+        for x in 66..=127 {
+            assert_eq!(hysteresis.update(x), Some(x));
+        }
+        // back down needs more than the band, then every step is sent again
+        assert_eq!(hysteresis.update(126), None);
+        assert_eq!(hysteresis.update(125), Some(125));
+        assert_eq!(hysteresis.update(124), Some(124));
+        assert_eq!(hysteresis.update(125), None);
+        assert_eq!(hysteresis.value(), Some(124));
+    }
+
+    #[test]
+    fn hysteresis_limits() {
+        let mut hysteresis = Hysteresis::new(4u16).with_limits(10, 4085);
+        assert_eq!(hysteresis.update(2000), Some(2000));
+        assert_eq!(hysteresis.update(4090), Some(4085));
+        assert_eq!(hysteresis.update(4083), None);
+        assert_eq!(hysteresis.update(3), Some(10));
+        hysteresis.reset();
+        assert_eq!(hysteresis.update(2000), Some(2000));
+    }
+
+    #[test]
+    fn ema_settles() {
+        // at any sample width
+        let mut ema = Ema::<u32>::new(3);
+        assert_eq!(ema.update(100_000), 100_000);
+        let mut ema = Ema::<u8>::new(2);
+        assert_eq!(ema.update(0), 0);
+        let settled = (0..40).map(|_| ema.update(255)).last();
+        assert_eq!(settled, Some(255));
+    }
+}
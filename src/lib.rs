@@ -1,16 +1,24 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger
+#[cfg(target_os = "none")]
 use stm32f1xx_hal as _; // memory layout
 
+#[cfg(target_os = "none")]
 use panic_probe as _;
 
-mod audio_midi;
+pub mod buttons;
+pub mod filter;
+pub mod midi;
+pub mod pickup;
+pub mod settings;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(target_os = "none")]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
@@ -0,0 +1,291 @@
+//! Arpeggiator
+//!
+//! Plays the held notes one after the other, over a range of octaves. Notes are fed
+//! from any source, e.g. messages received from the host or local buttons.
+//!
+//! Like the `Sequencer`, the arpeggiator keeps its own timeline in µs. Call `poll`
+//! with the current time until it returns `None`, then call it again at `next_due`.
+//! Playback starts with the first held note and stops when all are released.
+
+use super::message::MidiMessage;
+use super::timing::{first_due, is_due, Rng, StepTimer};
+use heapless::Vec;
+
+/// Notes that can be held at the same time
+pub const MAX_NOTES: usize = 16;
+
+/// Octave range of `Arpeggiator::set_octaves`
+pub const MAX_OCTAVES: u8 = 4;
+
+/// Order in which the held notes are played
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ArpMode {
+    /// Lowest to highest, then the next octave
+    Up,
+    /// Highest to lowest, then the octave below
+    Down,
+    /// Up and down again, without repeating the top and bottom notes
+    UpDown,
+    /// Random notes from the range
+    Random,
+    /// In the order the notes were pressed
+    AsPlayed,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    note: u8,
+    velocity: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Sounding {
+    channel: u8,
+    note: u8,
+    off_at: u32,
+}
+
+pub struct Arpeggiator {
+    // in the order they were pressed
+    held: Vec<Held, MAX_NOTES>,
+    mode: ArpMode,
+    octaves: u8,
+    channel: u8,
+    timer: StepTimer,
+    gate: u8,
+    running: bool,
+    // position in the pattern
+    index: usize,
+    sounding: Option<Sounding>,
+    // note-on following the note-off of the previous step
+    pending: Option<MidiMessage>,
+    rng: Rng,
+}
+
+impl Arpeggiator {
+    /// Arpeggiator playing eighth notes (2 steps per beat) upwards over one octave
+    pub fn new(channel: u8, bpm: u32) -> Self {
+        Arpeggiator {
+            held: Vec::new(),
+            mode: ArpMode::Up,
+            octaves: 1,
+            channel: channel & 0x0f,
+            timer: StepTimer::new(bpm, 2),
+            gate: 50,
+            running: false,
+            index: 0,
+            sounding: None,
+            pending: None,
+            rng: Rng::new(),
+        }
+    }
+
+    pub fn mode(&self) -> ArpMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ArpMode) {
+        self.mode = mode;
+        self.index = 0;
+    }
+
+    /// Sets the range in octaves, clamped to `1..=MAX_OCTAVES`
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves.clamp(1, MAX_OCTAVES);
+    }
+
+    pub fn set_channel(&mut self, channel: u8) {
+        self.channel = channel & 0x0f;
+    }
+
+    pub fn bpm(&self) -> u32 {
+        self.timer.bpm()
+    }
+
+    /// Changes the tempo from the next step on, clamped to `MIN_BPM..=MAX_BPM`
+    pub fn set_bpm(&mut self, bpm: u32) {
+        self.timer.set_bpm(bpm);
+    }
+
+    /// Sets the rate in steps per beat, e.g. 4 for sixteenths and 3 for eighth triplets
+    pub fn set_rate(&mut self, steps_per_beat: u32) {
+        self.timer.set_steps_per_beat(steps_per_beat);
+    }
+
+    /// Sets the note length in percent of a step, clamped to `1..=100`
+    pub fn set_gate(&mut self, gate: u8) {
+        self.gate = gate.clamp(1, 100);
+    }
+
+    /// Seeds the random generator used by `ArpMode::Random`
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng.seed(seed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Holds a note, starts playing at `now` if it is the first one
+    ///
+    /// Notes beyond `MAX_NOTES` are ignored.
+    pub fn note_on(&mut self, note: u8, velocity: u8, now: u32) {
+        if velocity == 0 {
+            return self.note_off(note);
+        }
+        let note = note & 0x7f;
+        if let Some(held) = self.held.iter_mut().find(|held| held.note == note) {
+            held.velocity = velocity & 0x7f;
+            return;
+        }
+        if self
+            .held
+            .push(Held {
+                note,
+                velocity: velocity & 0x7f,
+            })
+            .is_ok()
+            && !self.running
+        {
+            self.running = true;
+            self.index = 0;
+            self.timer.start(now);
+        }
+    }
+
+    /// Releases a note, stops playing when it was the last one
+    pub fn note_off(&mut self, note: u8) {
+        let note = note & 0x7f;
+        self.held.retain(|held| held.note != note);
+        if self.held.is_empty() {
+            self.running = false;
+            self.pending = None;
+        }
+    }
+
+    /// Releases all notes
+    pub fn release_all(&mut self) {
+        self.held.clear();
+        self.running = false;
+        self.pending = None;
+    }
+
+    /// Holds and releases notes for received note-on and note-off messages, returns
+    /// whether `msg` was one of those
+    pub fn feed(&mut self, msg: &MidiMessage, now: u32) -> bool {
+        match *msg {
+            MidiMessage::NoteOn { note, velocity, .. } => self.note_on(note, velocity, now),
+            MidiMessage::NoteOff { note, .. } => self.note_off(note),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Returns the next message due at `now`, call until it returns `None`
+    pub fn poll(&mut self, now: u32) -> Option<MidiMessage> {
+        if let Some(msg) = self.pending.take() {
+            return Some(msg);
+        }
+
+        if let Some(sounding) = self.sounding {
+            if is_due(sounding.off_at, now) {
+                self.sounding = None;
+                return Some(MidiMessage::note_off(sounding.channel, sounding.note));
+            }
+        }
+
+        while self.running && is_due(self.timer.next_at(), now) {
+            let at = self.timer.advance();
+            let (note, velocity) = match self.next_note() {
+                Some(next) => next,
+                None => continue,
+            };
+
+            let on = MidiMessage::NoteOn {
+                channel: self.channel,
+                note,
+                velocity,
+            };
+            let previous = self.sounding.replace(Sounding {
+                channel: self.channel,
+                note,
+                off_at: at.wrapping_add(self.timer.step_us(self.gate as u32)),
+            });
+            return match previous {
+                Some(previous) => {
+                    self.pending = Some(on);
+                    Some(MidiMessage::note_off(previous.channel, previous.note))
+                }
+                None => Some(on),
+            };
+        }
+        None
+    }
+
+    /// Time at which `poll` has something to do next, `None` when stopped and silent
+    pub fn next_due(&self, now: u32) -> Option<u32> {
+        if self.pending.is_some() {
+            return Some(now);
+        }
+        let step = if self.running {
+            Some(self.timer.next_at())
+        } else {
+            None
+        };
+        let off = self.sounding.map(|sounding| sounding.off_at);
+        first_due(off.into_iter().chain(step), now)
+    }
+
+    // Picks the note of the current step and moves on, `None` when it is out of range
+    fn next_note(&mut self) -> Option<(u8, u8)> {
+        let notes = self.held.len();
+        let len = notes * self.octaves as usize;
+        let i = match self.mode {
+            ArpMode::Random => self.rng.next_u32() as usize % len,
+            ArpMode::UpDown if len > 1 => {
+                let period = 2 * len - 2;
+                let i = self.index % period;
+                self.index = (i + 1) % period;
+                if i < len {
+                    i
+                } else {
+                    period - i
+                }
+            }
+            _ => {
+                let i = self.index % len;
+                self.index = (i + 1) % len;
+                match self.mode {
+                    ArpMode::Down => len - 1 - i,
+                    _ => i,
+                }
+            }
+        };
+
+        let octave = (i / notes) as u8;
+        let held = match self.mode {
+            ArpMode::AsPlayed => self.held[i % notes],
+            _ => {
+                // the held notes in ascending order, without sorting them
+                let rank = i % notes;
+                *self
+                    .held
+                    .iter()
+                    .find(|held| {
+                        self.held
+                            .iter()
+                            .filter(|other| other.note < held.note)
+                            .count()
+                            == rank
+                    })
+                    .unwrap()
+            }
+        };
+        let note = held.note + 12 * octave;
+        if note > 0x7f {
+            None
+        } else {
+            Some((note, held.velocity))
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! MIDI clock
+//!
+//! The clock runs at 24 pulses (Timing Clock messages) per quarter note. Start,
+//! Stop and Continue control the transport of the receiving devices, the Song
+//! Position Pointer sets where Continue resumes playback.
+//!
+//! `ClockMaster` generates the clock, `ClockFollower` tracks an incoming one.
+
+use super::message::MidiMessage;
+use super::timing::MICROS_PER_MINUTE;
+
+/// Timing clocks per quarter note
+pub const PPQN: u32 = 24;
+
+/// Tempo range accepted by `ClockMaster::set_bpm`
+pub const MIN_BPM: u32 = 20;
+pub const MAX_BPM: u32 = 300;
+
+/// Timing clocks per Song Position Pointer unit (a sixteenth note)
+pub const CLOCKS_PER_SPP: u32 = 6;
+
+// Clocks further apart than at `MIN_BPM` mean the clock stopped, the tempo is unknown
+const MAX_INTERVAL_US: u32 = (MICROS_PER_MINUTE / (MIN_BPM * PPQN) as u64) as u32;
+
+// Tempo estimation, exponential moving average of the clock interval with weight
+// 1/2^EMA_SHIFT for the newest one, kept with EMA_SHIFT extra fraction bits
+const EMA_SHIFT: u32 = 3;
+
+/// Generates the MIDI clock at a given tempo
+///
+/// Call `tick` at the time it returned the previous time, e.g. by scheduling a task
+/// on a monotonic timer. The intervals are exact in the long run, rounding errors
+/// do not accumulate.
+pub struct ClockMaster {
+    bpm: u32,
+    running: bool,
+    // clocks sent since the last tempo change, and the time in µs they took
+    ticks: u32,
+    ticks_us: u64,
+}
+
+impl ClockMaster {
+    pub const fn new(bpm: u32) -> Self {
+        ClockMaster {
+            bpm: clamp_bpm(bpm),
+            running: false,
+            ticks: 0,
+            ticks_us: 0,
+        }
+    }
+
+    pub fn bpm(&self) -> u32 {
+        self.bpm
+    }
+
+    /// Changes the tempo from the next clock on, clamped to `MIN_BPM..=MAX_BPM`
+    pub fn set_bpm(&mut self, bpm: u32) {
+        self.bpm = clamp_bpm(bpm);
+        self.ticks = 0;
+        self.ticks_us = 0;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Starts playback from the beginning of the song
+    pub fn start(&mut self) -> MidiMessage {
+        self.running = true;
+        MidiMessage::Start
+    }
+
+    /// Stops playback, the clock keeps running so receivers stay in sync
+    pub fn stop(&mut self) -> MidiMessage {
+        self.running = false;
+        MidiMessage::Stop
+    }
+
+    /// Resumes playback from where it was stopped
+    pub fn resume(&mut self) -> MidiMessage {
+        self.running = true;
+        MidiMessage::Continue
+    }
+
+    /// Returns the Timing Clock message to send now, and the time in µs until the
+    /// next call
+    pub fn tick(&mut self) -> (MidiMessage, u32) {
+        self.ticks += 1;
+        let next_us = self.ticks as u64 * MICROS_PER_MINUTE / (self.bpm * PPQN) as u64;
+        let interval = (next_us - self.ticks_us) as u32;
+        self.ticks_us = next_us;
+        (MidiMessage::TimingClock, interval)
+    }
+}
+
+const fn clamp_bpm(bpm: u32) -> u32 {
+    if bpm < MIN_BPM {
+        MIN_BPM
+    } else if bpm > MAX_BPM {
+        MAX_BPM
+    } else {
+        bpm
+    }
+}
+
+/// Position in the song, in timing clocks since the start
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, defmt::Format)]
+pub struct Position {
+    pub clocks: u32,
+}
+
+impl Position {
+    /// Quarter notes since the start
+    pub fn beat(&self) -> u32 {
+        self.clocks / PPQN
+    }
+
+    /// Timing clock within the beat, `0..PPQN`
+    pub fn tick(&self) -> u32 {
+        self.clocks % PPQN
+    }
+
+    /// Sixteenth notes since the start, as in the Song Position Pointer
+    pub fn sixteenth(&self) -> u32 {
+        self.clocks / CLOCKS_PER_SPP
+    }
+}
+
+/// Transport and timing changes reported by `ClockFollower::feed`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ClockEvent {
+    /// Playback starts at the beginning of the song
+    Start,
+    /// Playback resumes at the position
+    Continue(Position),
+    /// Playback stopped at the position
+    Stop(Position),
+    /// The song position was moved while stopped
+    Locate(Position),
+    /// A timing clock while playing, at the position it marks
+    Tick(Position),
+}
+
+/// Follows an incoming MIDI clock
+///
+/// Feed it every received real-time message and the Song Position Pointer together
+/// with the time of arrival in µs, e.g. from a monotonic timer. The timestamps may
+/// wrap around. Other messages are ignored.
+pub struct ClockFollower {
+    running: bool,
+    // the next clock played is at this position
+    position: Position,
+    last_clock_us: Option<u32>,
+    // estimated clock interval in µs, with EMA_SHIFT fraction bits, 0 if unknown
+    interval: u32,
+}
+
+impl ClockFollower {
+    pub const fn new() -> Self {
+        ClockFollower {
+            running: false,
+            position: Position { clocks: 0 },
+            last_clock_us: None,
+            interval: 0,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Position of the next timing clock to be played
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Estimated time between timing clocks in µs, `None` while no clock is received
+    pub fn interval_us(&self) -> Option<u32> {
+        match self.interval {
+            0 => None,
+            interval => Some((interval + (1 << (EMA_SHIFT - 1))) >> EMA_SHIFT),
+        }
+    }
+
+    /// Estimated tempo in beats per minute (rounded), `None` while no clock is received
+    pub fn bpm(&self) -> Option<u32> {
+        self.bpm_centi().map(|bpm| (bpm + 50) / 100)
+    }
+
+    /// Estimated tempo in 1/100 beats per minute
+    pub fn bpm_centi(&self) -> Option<u32> {
+        match self.interval {
+            0 => None,
+            interval => {
+                let per_clock = MICROS_PER_MINUTE * 100 * (1 << EMA_SHIFT) / PPQN as u64;
+                Some(((per_clock + interval as u64 / 2) / interval as u64) as u32)
+            }
+        }
+    }
+
+    pub fn feed(&mut self, msg: &MidiMessage, now_us: u32) -> Option<ClockEvent> {
+        match *msg {
+            MidiMessage::TimingClock => {
+                self.clock(now_us);
+                if self.running {
+                    let position = self.position;
+                    self.position.clocks = self.position.clocks.wrapping_add(1);
+                    Some(ClockEvent::Tick(position))
+                } else {
+                    None
+                }
+            }
+            MidiMessage::Start => {
+                self.running = true;
+                self.position = Position::default();
+                Some(ClockEvent::Start)
+            }
+            MidiMessage::Continue => {
+                self.running = true;
+                Some(ClockEvent::Continue(self.position))
+            }
+            MidiMessage::Stop => {
+                self.running = false;
+                Some(ClockEvent::Stop(self.position))
+            }
+            // only valid while stopped, ignored while playing as the spec demands
+            MidiMessage::SongPositionPointer(sixteenths) if !self.running => {
+                self.position.clocks = sixteenths as u32 * CLOCKS_PER_SPP;
+                Some(ClockEvent::Locate(self.position))
+            }
+            _ => None,
+        }
+    }
+
+    // Updates the tempo estimate
+    fn clock(&mut self, now_us: u32) {
+        let last = self.last_clock_us.replace(now_us);
+        let elapsed = match last {
+            Some(last) => now_us.wrapping_sub(last),
+            None => return,
+        };
+        if elapsed > MAX_INTERVAL_US {
+            // the clock was paused, start over
+            self.interval = 0;
+        } else if self.interval == 0 {
+            self.interval = elapsed << EMA_SHIFT;
+        } else {
+            self.interval = self.interval - (self.interval >> EMA_SHIFT) + elapsed;
+        }
+    }
+}
+
+impl Default for ClockFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
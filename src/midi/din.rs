@@ -0,0 +1,260 @@
+//! Classic 5-pin DIN MIDI over a serial port
+//!
+//! The UART has to be configured for 31250 baud, 8 data bits, no parity and 1 stop
+//! bit, e.g. `Config::default().baudrate(din::BAUD_RATE.bps())` on USART1/2.
+//!
+//! Transmitted channel messages use running status, i.e. the status byte is left out
+//! if it is the same as the one of the previous channel message. Real-time messages
+//! are sent ahead of any other queued bytes.
+
+use embedded_hal::serial::{Read, Write};
+use heapless::Deque;
+
+use super::message::MidiMessage;
+use super::packet::{cin, EventPacket};
+use super::parser::PacketParser;
+use super::PACKETS_PER_TRANSFER;
+
+/// DIN MIDI baud rate
+pub const BAUD_RATE: u32 = 31_250;
+
+/// Real-time bytes waiting for the UART, a full USB transfer of timing clocks
+const REALTIME_BUF_LEN: usize = PACKETS_PER_TRANSFER;
+
+/// Typed MIDI messages over a serial port
+pub struct DinMidi<TX, RX> {
+    tx: DinTx<TX>,
+    rx: DinRx<RX>,
+}
+
+impl<TX, RX> DinMidi<TX, RX>
+where
+    TX: Write<u8>,
+    RX: Read<u8>,
+{
+    pub fn new(tx: TX, rx: RX) -> Self {
+        DinMidi {
+            tx: DinTx::new(tx),
+            rx: DinRx::new(rx),
+        }
+    }
+
+    /// See `DinTx::send`
+    pub fn send(&mut self, msg: &MidiMessage) -> nb::Result<(), TX::Error> {
+        self.tx.send(msg)
+    }
+
+    /// See `DinTx::flush`
+    pub fn flush(&mut self) -> nb::Result<(), TX::Error> {
+        self.tx.flush()
+    }
+
+    /// See `DinRx::read`
+    pub fn read(&mut self) -> nb::Result<MidiMessage, RX::Error> {
+        self.rx.read()
+    }
+
+    /// Splits into the transmit and receive halves
+    pub fn split(self) -> (DinTx<TX>, DinRx<RX>) {
+        (self.tx, self.rx)
+    }
+}
+
+/// Transmit half, queuing up to `N` bytes
+pub struct DinTx<TX, const N: usize = 32> {
+    tx: TX,
+    buf: Deque<u8, N>,
+    realtime: Deque<u8, REALTIME_BUF_LEN>,
+    running_status: Option<u8>,
+}
+
+impl<TX, const N: usize> DinTx<TX, N>
+where
+    TX: Write<u8>,
+{
+    pub fn new(tx: TX) -> Self {
+        DinTx {
+            tx,
+            buf: Deque::new(),
+            realtime: Deque::new(),
+            running_status: None,
+        }
+    }
+
+    /// Queues `msg` for transmission and starts sending it
+    ///
+    /// Returns `WouldBlock` if there is no room for the message, call `flush` and
+    /// try again.
+    pub fn send(&mut self, msg: &MidiMessage) -> nb::Result<(), TX::Error> {
+        self.send_packet(&EventPacket::from_message(0, msg))
+    }
+
+    /// Queues the MIDI bytes of a USB-MIDI event packet, including SysEx, the cable
+    /// number is ignored
+    pub fn send_packet(&mut self, packet: &EventPacket) -> nb::Result<(), TX::Error> {
+        let mut bytes = packet.midi_bytes();
+        let status = match bytes.first() {
+            Some(status) => *status,
+            None => return Ok(()),
+        };
+
+        if packet.cin() == cin::SINGLE_BYTE && status >= 0xf8 {
+            if self.realtime.push_back(status).is_err() {
+                self.flush()?;
+                return Err(nb::Error::WouldBlock);
+            }
+            return self.start();
+        }
+
+        let channel = (cin::NOTE_OFF..=cin::PITCH_BEND).contains(&packet.cin());
+        if channel && self.running_status == Some(status) {
+            bytes = &bytes[1..];
+        }
+        if self.room() < bytes.len() {
+            self.flush()?;
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // system common messages and SysEx cancel running status
+        self.running_status = if channel { Some(status) } else { None };
+        for byte in bytes {
+            self.buf.push_back(*byte).ok();
+        }
+        self.start()
+    }
+
+    // Starts sending queued bytes, the UART being busy is fine as they are queued
+    fn start(&mut self) -> nb::Result<(), TX::Error> {
+        match self.flush() {
+            Err(nb::Error::WouldBlock) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Writes queued bytes to the UART until it is busy
+    pub fn flush(&mut self) -> nb::Result<(), TX::Error> {
+        while let Some(byte) = self.realtime.front().or_else(|| self.buf.front()) {
+            self.tx.write(*byte)?;
+            if self.realtime.pop_front().is_none() {
+                self.buf.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// Free space in the transmit queue, in bytes
+    pub fn room(&self) -> usize {
+        N - self.buf.len()
+    }
+
+    /// Whether `packets` event packets of any kind can be queued, e.g. the
+    /// `PACKETS_PER_TRANSFER` of a USB transfer
+    ///
+    /// Real-time bytes have their own queue, so this takes room in both queues.
+    pub fn has_room(&self, packets: usize) -> bool {
+        self.room() >= 3 * packets && REALTIME_BUF_LEN - self.realtime.len() >= packets
+    }
+
+    pub fn free(self) -> TX {
+        self.tx
+    }
+}
+
+/// Receive half
+pub struct DinRx<RX> {
+    rx: RX,
+    parser: PacketParser,
+    pending: Option<EventPacket>,
+}
+
+impl<RX> DinRx<RX>
+where
+    RX: Read<u8>,
+{
+    pub fn new(rx: RX) -> Self {
+        DinRx {
+            rx,
+            parser: PacketParser::new(0),
+            pending: None,
+        }
+    }
+
+    /// Reads bytes from the UART until a complete message has been received, SysEx
+    /// is skipped
+    pub fn read(&mut self) -> nb::Result<MidiMessage, RX::Error> {
+        loop {
+            if let Some(msg) = self.read_packet()?.message() {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Reads bytes from the UART until a USB-MIDI event packet is complete,
+    /// including SysEx packets
+    pub fn read_packet(&mut self) -> nb::Result<EventPacket, RX::Error> {
+        if let Some(packet) = self.pending.take() {
+            return Ok(packet);
+        }
+        loop {
+            let byte = self.rx.read()?;
+            let mut packets = self.parser.feed(byte);
+            if let Some(packet) = packets.next() {
+                self.pending = packets.next();
+                return Ok(packet);
+            }
+        }
+    }
+
+    pub fn free(self) -> RX {
+        self.rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a UART busy sending, nothing gets written
+    struct Busy;
+
+    impl Write<u8> for Busy {
+        type Error = ();
+
+        fn write(&mut self, _: u8) -> nb::Result<(), ()> {
+            Err(nb::Error::WouldBlock)
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn room_for_a_transfer_of_clocks() {
+        let mut tx = DinTx::<_, 64>::new(Busy);
+        assert!(tx.has_room(PACKETS_PER_TRANSFER));
+        for _ in 0..PACKETS_PER_TRANSFER {
+            assert_eq!(tx.send(&MidiMessage::TimingClock), Ok(()));
+        }
+        assert!(!tx.has_room(1));
+        assert_eq!(
+            tx.send(&MidiMessage::TimingClock),
+            Err(nb::Error::WouldBlock)
+        );
+    }
+
+    #[test]
+    fn room_for_a_transfer_of_notes() {
+        let mut tx = DinTx::<_, 64>::new(Busy);
+        for note in 0..PACKETS_PER_TRANSFER as u8 {
+            let msg = MidiMessage::NoteOn {
+                channel: note & 1,
+                note,
+                velocity: 100,
+            };
+            assert_eq!(tx.send(&msg), Ok(()));
+        }
+        assert_eq!(tx.room(), 64 - 3 * PACKETS_PER_TRANSFER);
+        assert!(!tx.has_room(PACKETS_PER_TRANSFER));
+    }
+}
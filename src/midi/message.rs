@@ -0,0 +1,220 @@
+//! Typed MIDI messages
+
+use super::packet::cin;
+
+/// A MIDI message, channels are 0..=15 and data bytes 0..=127
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyKeyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        control: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// 14 bit value, 0x2000 is centered
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    // System common
+    TimeCodeQuarterFrame(u8),
+    /// Position in MIDI beats (6 timing clocks) since the start of the song
+    SongPositionPointer(u16),
+    SongSelect(u8),
+    TuneRequest,
+    // System real-time
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+/// Center position of `MidiMessage::PitchBend`
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+
+impl MidiMessage {
+    /// Note off with velocity 0, as sent to end the notes played by the library
+    pub const fn note_off(channel: u8, note: u8) -> Self {
+        MidiMessage::NoteOff {
+            channel,
+            note,
+            velocity: 0,
+        }
+    }
+
+    /// Code Index Number and MIDI bytes for the USB-MIDI event packet
+    pub fn encode(&self) -> (u8, [u8; 3]) {
+        match *self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (cin::NOTE_OFF, voice(0x80, channel, note, velocity)),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (cin::NOTE_ON, voice(0x90, channel, note, velocity)),
+            MidiMessage::PolyKeyPressure {
+                channel,
+                note,
+                pressure,
+            } => (cin::POLY_KEY_PRESSURE, voice(0xa0, channel, note, pressure)),
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => (cin::CONTROL_CHANGE, voice(0xb0, channel, control, value)),
+            MidiMessage::ProgramChange { channel, program } => {
+                (cin::PROGRAM_CHANGE, voice(0xc0, channel, program, 0))
+            }
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                (cin::CHANNEL_PRESSURE, voice(0xd0, channel, pressure, 0))
+            }
+            MidiMessage::PitchBend { channel, value } => (
+                cin::PITCH_BEND,
+                voice(0xe0, channel, value as u8, (value >> 7) as u8),
+            ),
+            MidiMessage::TimeCodeQuarterFrame(data) => {
+                (cin::SYSTEM_COMMON_2, [0xf1, data & 0x7f, 0])
+            }
+            MidiMessage::SongPositionPointer(beats) => (
+                cin::SYSTEM_COMMON_3,
+                [0xf2, beats as u8 & 0x7f, (beats >> 7) as u8 & 0x7f],
+            ),
+            MidiMessage::SongSelect(song) => (cin::SYSTEM_COMMON_2, [0xf3, song & 0x7f, 0]),
+            MidiMessage::TuneRequest => (cin::SYSTEM_COMMON_1, [0xf6, 0, 0]),
+            MidiMessage::TimingClock => (cin::SINGLE_BYTE, [0xf8, 0, 0]),
+            MidiMessage::Start => (cin::SINGLE_BYTE, [0xfa, 0, 0]),
+            MidiMessage::Continue => (cin::SINGLE_BYTE, [0xfb, 0, 0]),
+            MidiMessage::Stop => (cin::SINGLE_BYTE, [0xfc, 0, 0]),
+            MidiMessage::ActiveSensing => (cin::SINGLE_BYTE, [0xfe, 0, 0]),
+            MidiMessage::SystemReset => (cin::SINGLE_BYTE, [0xff, 0, 0]),
+        }
+    }
+
+    /// Decodes the MIDI bytes of a USB-MIDI event packet with the given Code Index Number
+    ///
+    /// SysEx packets (CIN 0x4..=0x7, except Tune Request) are not handled here.
+    pub fn decode(cin: u8, midi: [u8; 3]) -> Option<Self> {
+        let channel = midi[0] & 0x0f;
+        let (data1, data2) = (midi[1] & 0x7f, midi[2] & 0x7f);
+        let msg = match (cin, midi[0] & 0xf0) {
+            (cin::NOTE_OFF, 0x80) => MidiMessage::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            (cin::NOTE_ON, 0x90) => MidiMessage::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            (cin::POLY_KEY_PRESSURE, 0xa0) => MidiMessage::PolyKeyPressure {
+                channel,
+                note: data1,
+                pressure: data2,
+            },
+            (cin::CONTROL_CHANGE, 0xb0) => MidiMessage::ControlChange {
+                channel,
+                control: data1,
+                value: data2,
+            },
+            (cin::PROGRAM_CHANGE, 0xc0) => MidiMessage::ProgramChange {
+                channel,
+                program: data1,
+            },
+            (cin::CHANNEL_PRESSURE, 0xd0) => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data1,
+            },
+            (cin::PITCH_BEND, 0xe0) => MidiMessage::PitchBend {
+                channel,
+                value: data1 as u16 | (data2 as u16) << 7,
+            },
+            (
+                cin::SYSTEM_COMMON_1
+                | cin::SYSTEM_COMMON_2
+                | cin::SYSTEM_COMMON_3
+                | cin::SINGLE_BYTE,
+                0xf0,
+            ) => return Self::decode_system(cin, midi[0], data1, data2),
+            _ => return None,
+        };
+        Some(msg)
+    }
+
+    fn decode_system(cin: u8, status: u8, data1: u8, data2: u8) -> Option<Self> {
+        let msg = match (cin, status) {
+            (cin::SYSTEM_COMMON_2, 0xf1) => MidiMessage::TimeCodeQuarterFrame(data1),
+            (cin::SYSTEM_COMMON_3, 0xf2) => {
+                MidiMessage::SongPositionPointer(data1 as u16 | (data2 as u16) << 7)
+            }
+            (cin::SYSTEM_COMMON_2, 0xf3) => MidiMessage::SongSelect(data1),
+            (cin::SYSTEM_COMMON_1 | cin::SINGLE_BYTE, 0xf6) => MidiMessage::TuneRequest,
+            (cin::SINGLE_BYTE, 0xf8) => MidiMessage::TimingClock,
+            (cin::SINGLE_BYTE, 0xfa) => MidiMessage::Start,
+            (cin::SINGLE_BYTE, 0xfb) => MidiMessage::Continue,
+            (cin::SINGLE_BYTE, 0xfc) => MidiMessage::Stop,
+            (cin::SINGLE_BYTE, 0xfe) => MidiMessage::ActiveSensing,
+            (cin::SINGLE_BYTE, 0xff) => MidiMessage::SystemReset,
+            _ => return None,
+        };
+        Some(msg)
+    }
+
+    /// Channel of channel voice messages, `None` for system messages
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyKeyPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// System real-time messages may be sent at any time, even inside other messages
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            MidiMessage::TimingClock
+                | MidiMessage::Start
+                | MidiMessage::Continue
+                | MidiMessage::Stop
+                | MidiMessage::ActiveSensing
+                | MidiMessage::SystemReset
+        )
+    }
+}
+
+fn voice(status: u8, channel: u8, data1: u8, data2: u8) -> [u8; 3] {
+    [status | (channel & 0x0f), data1 & 0x7f, data2 & 0x7f]
+}
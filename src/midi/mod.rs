@@ -0,0 +1,583 @@
+//! Minimal USB-MIDI 1.0 class
+//!
+//! A single MIDIStreaming interface (behind an empty AudioControl interface)
+//! with one bulk OUT endpoint (host -> device) and one bulk IN endpoint
+//! (device -> host).
+//!
+//! Each port shows up as a separate MIDI port on the host. Port `n` is made up of
+//! four jacks:
+//!
+//! - embedded IN jack `4n + 1`, fed by the OUT endpoint (cable `n`)
+//! - external IN jack `4n + 2`
+//! - embedded OUT jack `4n + 3`, feeding the IN endpoint (cable `n`), from jack `4n + 2`
+//! - external OUT jack `4n + 4`, from jack `4n + 1`
+//!
+//! Descriptor layout follows the USB Device Class Definition for MIDI Devices 1.0,
+//! see https://www.usb.org/sites/default/files/midi10.pdf
+
+use heapless::Vec;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+pub mod arpeggiator;
+pub mod clock;
+pub mod din;
+pub mod message;
+pub mod packet;
+pub mod parser;
+pub mod queue;
+pub mod rpn;
+pub mod sequencer;
+pub mod sysex;
+mod timing;
+
+pub use message::MidiMessage;
+pub use packet::{cin, EventPacket, EventPackets};
+pub use queue::{OverflowPolicy, TxQueue};
+pub use rpn::{ParameterChange, ParameterKind, ParameterTracker};
+pub use sysex::{Identity, ManufacturerId, SysExError, SysExReceiver};
+
+/// https://www.usb.org/defined-class-codes#anchor_BaseClass01h
+pub const USB_CLASS_AUDIO: u8 = 0x01;
+const USB_SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const USB_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+const MS_HEADER: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+
+const EMBEDDED: u8 = 0x01;
+const EXTERNAL: u8 = 0x02;
+
+/// Size of the bulk endpoints, each USB-MIDI event packet is 4 bytes
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+const MS_HEADER_LEN: usize = 7;
+/// `DescriptorWriter::endpoint` writes the standard 7 byte endpoint descriptor
+const ENDPOINT_LEN: usize = 7;
+/// Configuration, AudioControl interface and header, MIDIStreaming interface
+const CONFIG_OVERHEAD_LEN: usize = 9 + 9 + 9 + 9;
+/// usb-device is built with the `control-buffer-256` feature
+const CONTROL_BUFFER_LEN: usize = 256;
+
+/// Descriptors of the MIDIStreaming interface
+enum MsDescriptor<'d> {
+    /// Descriptor type and payload
+    ClassSpecific(u8, &'d [u8]),
+    OutEndpoint,
+    InEndpoint,
+}
+
+impl MsDescriptor<'_> {
+    /// Length of the descriptor, including its length and type bytes
+    fn len(&self) -> usize {
+        match self {
+            MsDescriptor::ClassSpecific(_, descriptor) => 2 + descriptor.len(),
+            MsDescriptor::OutEndpoint | MsDescriptor::InEndpoint => ENDPOINT_LEN,
+        }
+    }
+}
+
+/// MIDIStreaming descriptor bytes per port, 2 IN jacks of 6 bytes, 2 OUT jacks of 9
+/// bytes and 1 jack ID in each class specific endpoint descriptor
+const PORT_LEN: usize = 2 * 6 + 2 * 9 + 2;
+/// MIDIStreaming descriptor bytes besides the ports, the header, the endpoints and
+/// their class specific descriptors
+const MS_FIXED_LEN: usize = MS_HEADER_LEN + 2 * ENDPOINT_LEN + 2 * 4;
+/// Ports whose descriptors fit in the control buffer
+const PORTS_IN_BUFFER: usize = (CONTROL_BUFFER_LEN - CONFIG_OVERHEAD_LEN - MS_FIXED_LEN) / PORT_LEN;
+
+/// Maximum number of ports
+///
+/// The whole configuration descriptor is assembled in the control buffer, with
+/// `control-buffer-256` it has room for 5 ports. The 4 bit cable number would allow 16.
+pub const MAX_PORTS: usize = if PORTS_IN_BUFFER < 16 {
+    PORTS_IN_BUFFER
+} else {
+    16
+};
+
+/// Highest controller of a 14 bit Control Change, the MSB of a pair with the LSB on
+/// `ctrl_nr + 32`
+pub const MAX_CC_14BIT: u8 = 31;
+
+/// Event packets queued for transmission
+pub const TX_QUEUE_LEN: usize = 64;
+/// Event packets in a full bulk transfer
+pub const PACKETS_PER_TRANSFER: usize = MAX_PACKET_SIZE as usize / 4;
+
+/// USB-MIDI class, exposing one or more ports (virtual cables) to the host
+pub struct MidiClass<'a, B: UsbBus> {
+    audio_if: InterfaceNumber,
+    midi_if: InterfaceNumber,
+    out_ep: EndpointOut<'a, B>,
+    in_ep: EndpointIn<'a, B>,
+    ports: &'static [&'static str],
+    port_names: Vec<Option<StringIndex>, MAX_PORTS>,
+    rx_buf: [u8; MAX_PACKET_SIZE as usize],
+    tx_queue: TxQueue,
+    // device ID and identity to answer Identity Requests with
+    identity: Option<(u8, Identity)>,
+    // one per cable, SysEx on different cables may be interleaved
+    identity_rx: [SysExReceiver<{ sysex::IDENTITY_REQUEST_LEN }>; MAX_PORTS],
+}
+
+impl<B: UsbBus> MidiClass<'_, B> {
+    /// Allocates the interfaces and endpoints of the class, with a single port
+    pub fn new(alloc: &UsbBusAllocator<B>) -> MidiClass<'_, B> {
+        Self::with_ports(alloc, &[""])
+    }
+
+    /// Allocates the class with one port per name, port `n` uses cable `n`
+    ///
+    /// The names are shown by the host, an empty name leaves it up to the host.
+    ///
+    /// ```ignore
+    /// const PORTS: &[&str] = &["Knobs", "Buttons"];
+    /// let midi = MidiClass::with_ports(&usb_bus, PORTS);
+    /// ```
+    pub fn with_ports<'a>(
+        alloc: &'a UsbBusAllocator<B>,
+        ports: &'static [&'static str],
+    ) -> MidiClass<'a, B> {
+        assert!(!ports.is_empty(), "midi: no ports");
+        assert!(ports.len() <= MAX_PORTS, "midi: too many ports");
+
+        let midi = MidiClass {
+            audio_if: alloc.interface(),
+            midi_if: alloc.interface(),
+            out_ep: alloc.bulk(MAX_PACKET_SIZE),
+            in_ep: alloc.bulk(MAX_PACKET_SIZE),
+            ports,
+            port_names: ports
+                .iter()
+                .map(|name| (!name.is_empty()).then(|| alloc.string()))
+                .collect(),
+            rx_buf: [0; MAX_PACKET_SIZE as usize],
+            tx_queue: TxQueue::new(OverflowPolicy::DropNewest),
+            identity: None,
+            identity_rx: [(); MAX_PORTS].map(|_| SysExReceiver::new()),
+        };
+
+        // the whole configuration descriptor is assembled in the control buffer
+        let config_length = CONFIG_OVERHEAD_LEN + midi.ms_total_length() as usize;
+        assert!(
+            config_length <= CONTROL_BUFFER_LEN,
+            "midi: configuration descriptor too long for the control buffer"
+        );
+
+        midi
+    }
+
+    /// Sets how the transmit queue behaves when the host does not keep up
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.tx_queue.set_policy(policy);
+    }
+
+    /// Answers Universal SysEx Identity Requests for `device_id` with `identity`
+    ///
+    /// Requests are recognized in `poll_events` and answered on the cable they were
+    /// received on. They are still passed on to the application.
+    ///
+    /// ```ignore
+    /// let identity = Identity::new(ManufacturerId::NON_COMMERCIAL, 0x0001, 0x0001);
+    /// midi.set_identity(sysex::ALL_CALL, identity);
+    /// ```
+    pub fn set_identity(&mut self, device_id: u8, identity: Identity) {
+        self.identity = Some((device_id & 0x7f, identity));
+    }
+
+    /// Number of ports (virtual cables)
+    pub fn ports(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// Queues `msg` on cable 0
+    pub fn send(&mut self, msg: MidiMessage) -> Result<()> {
+        self.send_packet(EventPacket::from_message(0, &msg))
+    }
+
+    /// Queues `msg` on the given cable
+    pub fn send_to(&mut self, cable: u8, msg: MidiMessage) -> Result<()> {
+        self.send_packet(EventPacket::from_message(cable, &msg))
+    }
+
+    /// Queues a single event packet for transmission
+    ///
+    /// Queued packets are sent in batches of up to 16 packets per bulk transfer, as
+    /// soon as the IN endpoint is free. If the queue is full a packet is dropped
+    /// according to the `OverflowPolicy`, failing with `BufferOverflow` if it was
+    /// this one.
+    pub fn send_packet(&mut self, packet: EventPacket) -> Result<()> {
+        self.tx_queue.push(packet)?;
+        self.flush()
+    }
+
+    /// Queues (part of) a SysEx message on the given cable, see `sysex::packets`
+    ///
+    /// Returns the number of bytes of `data` queued, which is less than its length if
+    /// the queue ran full. Call again with the remaining data once there is room.
+    pub fn send_sysex(&mut self, cable: u8, data: &[u8]) -> Result<usize> {
+        let len = data.len().min(self.tx_queue.room() * 3);
+        for packet in sysex::packets(cable, &data[..len]) {
+            self.tx_queue.push(packet).ok();
+        }
+        self.flush()?;
+        Ok(len)
+    }
+
+    /// Writes as many queued packets as fit in one bulk transfer
+    ///
+    /// Called automatically on send and whenever the previous transfer completed.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.tx_queue.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        let mut count = 0;
+        for (chunk, packet) in buf
+            .chunks_exact_mut(4)
+            .zip(self.tx_queue.iter().take(PACKETS_PER_TRANSFER))
+        {
+            chunk.copy_from_slice(&packet.0);
+            count += 1;
+        }
+
+        match self.in_ep.write(&buf[..4 * count]) {
+            Ok(_) => {
+                for _ in 0..count {
+                    self.tx_queue.pop();
+                }
+                Ok(())
+            }
+            // previous transfer still in progress, retried on completion
+            Err(UsbError::WouldBlock) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Number of packets waiting for transmission
+    pub fn queue_len(&self) -> usize {
+        self.tx_queue.len()
+    }
+
+    /// Number of packets dropped because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.tx_queue.dropped()
+    }
+
+    /// Reads a raw bulk transfer of event packets from the OUT endpoint
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        self.out_ep.read(buf)
+    }
+
+    /// Reads pending event packets sent by the host
+    ///
+    /// Call after `UsbDevice::poll` returned `true`, yields nothing if no data was
+    /// received. Use `EventPacket::message` to decode the packets.
+    pub fn poll_events(&mut self) -> Result<EventPackets<'_>> {
+        let len = match self.out_ep.read(&mut self.rx_buf) {
+            Ok(len) => len,
+            Err(UsbError::WouldBlock) => 0,
+            Err(e) => return Err(e),
+        };
+        self.answer_identity_requests(len);
+        Ok(EventPackets::new(&self.rx_buf[..len]))
+    }
+
+    fn answer_identity_requests(&mut self, len: usize) {
+        let (device_id, identity) = match self.identity {
+            Some(identity) => identity,
+            None => return,
+        };
+        let rx_buf = self.rx_buf;
+        for packet in EventPackets::new(&rx_buf[..len]) {
+            let receiver = match self.identity_rx.get_mut(packet.cable() as usize) {
+                Some(receiver) => receiver,
+                None => continue,
+            };
+            // longer messages overflow the receiver, they are no requests anyway
+            let request = matches!(
+                receiver.push(&packet),
+                Ok(Some(data)) if sysex::is_identity_request(data, device_id)
+            );
+            if !request {
+                continue;
+            }
+
+            let reply = identity.reply(device_id);
+            // no reply rather than a truncated one if the queue is (almost) full
+            if reply.len() <= self.tx_queue.room() * 3 {
+                self.send_sysex(packet.cable(), &reply).ok();
+            }
+        }
+    }
+
+    /// String indices of the port names, 0 for no name
+    fn name_indices(&self) -> Vec<u8, MAX_PORTS> {
+        self.port_names
+            .iter()
+            .map(|name| name.map_or(0, u8::from))
+            .collect()
+    }
+
+    fn ms_total_length(&self) -> u16 {
+        ms_total_length(&self.name_indices())
+    }
+
+    /// Sends a Note Off message on `chan` (0..=15)
+    pub fn note_off(&mut self, chan: u8, key: u8, vel: u8) -> Result<()> {
+        self.send(MidiMessage::NoteOff {
+            channel: chan,
+            note: key,
+            velocity: vel,
+        })
+    }
+
+    /// Sends a Note On message on `chan` (0..=15)
+    pub fn note_on(&mut self, chan: u8, key: u8, vel: u8) -> Result<()> {
+        self.send(MidiMessage::NoteOn {
+            channel: chan,
+            note: key,
+            velocity: vel,
+        })
+    }
+
+    /// Sends a Control Change message on `chan` (0..=15)
+    pub fn ctrl(&mut self, chan: u8, ctrl_nr: u8, ctrl_data: u8) -> Result<()> {
+        self.send(MidiMessage::ControlChange {
+            channel: chan,
+            control: ctrl_nr,
+            value: ctrl_data,
+        })
+    }
+
+    /// Sends a 14 bit Control Change on `chan` (0..=15), as the MSB on controller
+    /// `ctrl_nr` followed by the LSB on controller `ctrl_nr + 32`
+    ///
+    /// Only controllers `0..=MAX_CC_14BIT` have an LSB pair, check CC numbers from
+    /// settings or the host before binding them to 14 bit values.
+    pub fn ctrl_14bit(&mut self, chan: u8, ctrl_nr: u8, ctrl_data: u16) -> Result<()> {
+        debug_assert!(ctrl_nr <= MAX_CC_14BIT, "controller without an LSB pair");
+        self.ctrl(chan, ctrl_nr, (ctrl_data >> 7) as u8)?;
+        self.ctrl(chan, ctrl_nr + 32, ctrl_data as u8)
+    }
+
+    /// Sets Registered Parameter `number` to `value` on `chan` (0..=15), see `rpn`
+    pub fn rpn(&mut self, chan: u8, number: u16, value: u16) -> Result<()> {
+        self.parameter(chan, ParameterKind::Registered, number, value)
+    }
+
+    /// Sets Non-Registered Parameter `number` to `value` on `chan` (0..=15), see `rpn`
+    pub fn nrpn(&mut self, chan: u8, number: u16, value: u16) -> Result<()> {
+        self.parameter(chan, ParameterKind::NonRegistered, number, value)
+    }
+
+    fn parameter(&mut self, chan: u8, kind: ParameterKind, number: u16, value: u16) -> Result<()> {
+        let change = ParameterChange {
+            channel: chan,
+            kind,
+            number,
+            value,
+        };
+        for msg in change.messages() {
+            self.send(msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // Interface 0, AudioControl
+        writer.interface(
+            self.audio_if,
+            USB_CLASS_AUDIO,
+            USB_SUBCLASS_AUDIOCONTROL,
+            0x00,
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MS_HEADER,
+                0x00, /* bcdADC (lsb) */
+                0x01, /* bcdADC (msb), 1.0 */
+                0x09, /* total length (lsb), this descriptor only */
+                0x00, /* total length (msb) */
+                0x01, /* nr of streaming interfaces */
+                self.midi_if.into(),
+            ],
+        )?;
+
+        // Interface 1, MIDIStreaming
+        writer.interface(
+            self.midi_if,
+            USB_CLASS_AUDIO,
+            USB_SUBCLASS_MIDISTREAMING,
+            0x00,
+        )?;
+        let total_length = self.ms_total_length();
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MS_HEADER,
+                0x00, /* bcdMSC (lsb) */
+                0x01, /* bcdMSC (msb), 1.0 */
+                total_length as u8,
+                (total_length >> 8) as u8,
+            ],
+        )?;
+
+        ms_descriptors(&self.name_indices(), |descriptor| match descriptor {
+            MsDescriptor::ClassSpecific(descriptor_type, descriptor) => {
+                writer.write(descriptor_type, descriptor)
+            }
+            MsDescriptor::OutEndpoint => writer.endpoint(&self.out_ep),
+            MsDescriptor::InEndpoint => writer.endpoint(&self.in_ep),
+        })?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.tx_queue.clear();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.in_ep.address() {
+            self.flush().ok();
+        }
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        self.port_names
+            .iter()
+            .position(|name| *name == Some(index))
+            .map(|port| self.ports[port])
+    }
+}
+
+/// Walks the MIDIStreaming descriptors of ports with string indices `names` (0 for no
+/// name), following the class specific header
+fn ms_descriptors(names: &[u8], mut f: impl FnMut(MsDescriptor) -> Result<()>) -> Result<()> {
+    for (port, &name) in names.iter().enumerate() {
+        let id = jack_id(port);
+        f(MsDescriptor::ClassSpecific(
+            CS_INTERFACE,
+            &[MIDI_IN_JACK, EMBEDDED, id, name],
+        ))?;
+        f(MsDescriptor::ClassSpecific(
+            CS_INTERFACE,
+            &[MIDI_IN_JACK, EXTERNAL, id + 1, 0x00],
+        ))?;
+        f(MsDescriptor::ClassSpecific(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK,
+                EMBEDDED,
+                id + 2,
+                0x01,   /* nr of input pins */
+                id + 1, /* source id, external IN jack */
+                0x01,   /* source pin */
+                name,
+            ],
+        ))?;
+        f(MsDescriptor::ClassSpecific(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK,
+                EXTERNAL,
+                id + 3,
+                0x01, /* nr of input pins */
+                id,   /* source id, embedded IN jack */
+                0x01, /* source pin */
+                0x00,
+            ],
+        ))?;
+    }
+
+    // the jacks associated with an endpoint, in cable number order
+    let mut jacks: Vec<u8, { MAX_PORTS + 2 }> = Vec::new();
+
+    f(MsDescriptor::OutEndpoint)?;
+    jacks
+        .extend_from_slice(&[MS_GENERAL, names.len() as u8])
+        .ok();
+    jacks.extend((0..names.len()).map(jack_id));
+    f(MsDescriptor::ClassSpecific(CS_ENDPOINT, &jacks))?; // embedded IN jacks
+
+    f(MsDescriptor::InEndpoint)?;
+    jacks.truncate(2);
+    jacks.extend((0..names.len()).map(|port| jack_id(port) + 2));
+    f(MsDescriptor::ClassSpecific(CS_ENDPOINT, &jacks)) // embedded OUT jacks
+}
+
+/// wTotalLength of the MIDIStreaming interface, the class specific header and all
+/// descriptors following it
+fn ms_total_length(names: &[u8]) -> u16 {
+    let mut len = MS_HEADER_LEN;
+    ms_descriptors(names, |descriptor| {
+        len += descriptor.len();
+        Ok(())
+    })
+    .ok();
+    len as u16
+}
+
+/// Id of the embedded IN jack of `port`, the other jacks of the port follow it
+fn jack_id(port: usize) -> u8 {
+    4 * port as u8 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ms_total_length_of_the_descriptors() {
+        let names = [0; MAX_PORTS];
+        for ports in 1..=MAX_PORTS {
+            let mut len = MS_HEADER_LEN;
+            let mut count = 0;
+            ms_descriptors(&names[..ports], |descriptor| {
+                len += descriptor.len();
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+            // 4 jacks per port, 2 endpoints with their class specific descriptors
+            assert_eq!(count, 4 * ports + 4);
+            assert_eq!(len, MS_FIXED_LEN + ports * PORT_LEN);
+            assert_eq!(ms_total_length(&names[..ports]) as usize, len);
+        }
+    }
+
+    #[test]
+    fn max_ports_fit_the_control_buffer() {
+        let config_len = CONFIG_OVERHEAD_LEN + ms_total_length(&[0; MAX_PORTS]) as usize;
+        assert_eq!(MAX_PORTS, 5);
+        assert!(config_len <= CONTROL_BUFFER_LEN);
+        // one more port does not fit
+        assert!(config_len + PORT_LEN > CONTROL_BUFFER_LEN);
+    }
+
+    #[test]
+    fn endpoints_list_the_embedded_jacks() {
+        let mut endpoints: Vec<Vec<u8, { MAX_PORTS + 2 }>, 2> = Vec::new();
+        ms_descriptors(&[0, 4], |descriptor| {
+            if let MsDescriptor::ClassSpecific(CS_ENDPOINT, jacks) = descriptor {
+                endpoints.push(Vec::from_slice(jacks).unwrap()).ok();
+            }
+            Ok(())
+        })
+        .unwrap();
+        // IN jacks on the OUT endpoint, OUT jacks on the IN endpoint
+        assert_eq!(endpoints[0], [MS_GENERAL, 2, 1, 5]);
+        assert_eq!(endpoints[1], [MS_GENERAL, 2, 3, 7]);
+    }
+}
@@ -0,0 +1,147 @@
+//! USB-MIDI event packets
+//!
+//! Every MIDI message is carried in a 4 byte packet, where the first byte holds the
+//! virtual cable number (upper nibble) and the Code Index Number (lower nibble),
+//! followed by up to 3 bytes of the MIDI message itself.
+
+use super::message::MidiMessage;
+
+/// Code Index Numbers (CIN), see table 4-1 of the USB-MIDI 1.0 specification
+pub mod cin {
+    /// Two-byte System Common message (MTC quarter frame, song select)
+    pub const SYSTEM_COMMON_2: u8 = 0x2;
+    /// Three-byte System Common message (song position pointer)
+    pub const SYSTEM_COMMON_3: u8 = 0x3;
+    /// SysEx starts or continues, 3 bytes
+    pub const SYSEX_START: u8 = 0x4;
+    /// Single-byte System Common message (tune request) or SysEx ends with 1 byte
+    pub const SYSTEM_COMMON_1: u8 = 0x5;
+    pub const SYSEX_END_1: u8 = 0x5;
+    /// SysEx ends with 2 bytes
+    pub const SYSEX_END_2: u8 = 0x6;
+    /// SysEx ends with 3 bytes
+    pub const SYSEX_END_3: u8 = 0x7;
+    pub const NOTE_OFF: u8 = 0x8;
+    pub const NOTE_ON: u8 = 0x9;
+    pub const POLY_KEY_PRESSURE: u8 = 0xa;
+    pub const CONTROL_CHANGE: u8 = 0xb;
+    pub const PROGRAM_CHANGE: u8 = 0xc;
+    pub const CHANNEL_PRESSURE: u8 = 0xd;
+    pub const PITCH_BEND: u8 = 0xe;
+    /// Single byte, used for real-time messages
+    pub const SINGLE_BYTE: u8 = 0xf;
+
+    /// Number of MIDI bytes in a packet with the given Code Index Number
+    pub fn midi_len(cin: u8) -> usize {
+        match cin & 0x0f {
+            SYSTEM_COMMON_1 | SINGLE_BYTE => 1,
+            SYSTEM_COMMON_2 | SYSEX_END_2 | PROGRAM_CHANGE | CHANNEL_PRESSURE => 2,
+            SYSTEM_COMMON_3 | SYSEX_START | SYSEX_END_3 => 3,
+            NOTE_OFF | NOTE_ON | POLY_KEY_PRESSURE | CONTROL_CHANGE | PITCH_BEND => 3,
+            // 0x0 and 0x1 are reserved
+            _ => 0,
+        }
+    }
+
+    /// Code Index Number of a (non SysEx) message starting with `status`
+    pub fn from_status(status: u8) -> u8 {
+        match status {
+            0x80..=0xef => status >> 4,
+            0xf1 | 0xf3 => SYSTEM_COMMON_2,
+            0xf2 => SYSTEM_COMMON_3,
+            0xf6 => SYSTEM_COMMON_1,
+            _ => SINGLE_BYTE,
+        }
+    }
+}
+
+/// A single 4 byte USB-MIDI event packet
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct EventPacket(pub [u8; 4]);
+
+impl EventPacket {
+    /// Creates a packet from its header fields and the raw MIDI bytes
+    pub fn new(cable: u8, cin: u8, midi: [u8; 3]) -> Self {
+        EventPacket([
+            (cable & 0x0f) << 4 | (cin & 0x0f),
+            midi[0],
+            midi[1],
+            midi[2],
+        ])
+    }
+
+    /// Encodes `msg` on virtual `cable`
+    pub fn from_message(cable: u8, msg: &MidiMessage) -> Self {
+        let (cin, midi) = msg.encode();
+        Self::new(cable, cin, midi)
+    }
+
+    /// Virtual cable number (0..=15)
+    pub fn cable(&self) -> u8 {
+        self.0[0] >> 4
+    }
+
+    /// Code Index Number (0..=15)
+    pub fn cin(&self) -> u8 {
+        self.0[0] & 0x0f
+    }
+
+    /// The 3 MIDI bytes of the packet (unused bytes are zero)
+    pub fn midi(&self) -> [u8; 3] {
+        [self.0[1], self.0[2], self.0[3]]
+    }
+
+    /// The MIDI bytes of the packet, as many as its Code Index Number implies
+    pub fn midi_bytes(&self) -> &[u8] {
+        &self.0[1..1 + cin::midi_len(self.cin())]
+    }
+
+    /// Decodes the packet, `None` if it does not hold a (supported) MIDI message
+    pub fn message(&self) -> Option<MidiMessage> {
+        MidiMessage::decode(self.cin(), self.midi())
+    }
+}
+
+impl From<MidiMessage> for EventPacket {
+    /// Encodes `msg` on cable 0
+    fn from(msg: MidiMessage) -> Self {
+        EventPacket::from_message(0, &msg)
+    }
+}
+
+impl TryFrom<EventPacket> for MidiMessage {
+    type Error = EventPacket;
+
+    /// Fails with the original packet if it holds no (supported) MIDI message
+    fn try_from(packet: EventPacket) -> Result<Self, EventPacket> {
+        packet.message().ok_or(packet)
+    }
+}
+
+/// Iterator over the event packets of a received bulk transfer
+pub struct EventPackets<'a> {
+    chunks: core::slice::ChunksExact<'a, u8>,
+}
+
+impl<'a> EventPackets<'a> {
+    /// Trailing bytes not forming a complete packet are ignored
+    pub fn new(buf: &'a [u8]) -> Self {
+        EventPackets {
+            chunks: buf.chunks_exact(4),
+        }
+    }
+}
+
+impl Iterator for EventPackets<'_> {
+    type Item = EventPacket;
+
+    fn next(&mut self) -> Option<EventPacket> {
+        for chunk in &mut self.chunks {
+            // some hosts pad the transfer with empty packets
+            if chunk != [0, 0, 0, 0] {
+                return Some(EventPacket([chunk[0], chunk[1], chunk[2], chunk[3]]));
+            }
+        }
+        None
+    }
+}
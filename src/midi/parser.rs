@@ -0,0 +1,219 @@
+//! MIDI byte stream to USB-MIDI event packets
+//!
+//! Turns the bytes received on a DIN MIDI port into event packets with the right
+//! Code Index Number, ready to be sent to the host or decoded into messages.
+//!
+//! - running status is expanded, it is cancelled by system common messages, SysEx and
+//!   a stray 0xF7
+//! - real-time bytes are passed on immediately, also in the middle of other messages
+//!   or SysEx, without affecting them
+//! - SysEx is split into 3 byte packets, a SysEx interrupted by any other status byte
+//!   is terminated as if 0xF7 was received
+//! - data bytes without a status, incomplete messages and undefined status bytes are
+//!   dropped, parsing resumes with the next status byte
+//!
+//! Only depends on `core`, the tests below run on the host with
+//! `cargo test --lib --target x86_64-unknown-linux-gnu`.
+
+use super::packet::{cin, EventPacket};
+use super::sysex::{SYSEX_END, SYSEX_START};
+
+/// Streaming parser, one per input port
+pub struct PacketParser {
+    cable: u8,
+    status: Option<u8>,
+    sysex: bool,
+    data: [u8; 3],
+    len: usize,
+}
+
+/// Packets completed by a single byte, at most 2 (an interrupted SysEx and a Tune
+/// Request terminating it)
+pub struct Packets([Option<EventPacket>; 2]);
+
+impl Iterator for Packets {
+    type Item = EventPacket;
+
+    fn next(&mut self) -> Option<EventPacket> {
+        self.0[0].take().or_else(|| self.0[1].take())
+    }
+}
+
+impl PacketParser {
+    /// Packets are tagged with virtual `cable`
+    pub const fn new(cable: u8) -> Self {
+        PacketParser {
+            cable,
+            status: None,
+            sysex: false,
+            data: [0; 3],
+            len: 0,
+        }
+    }
+
+    /// Drops any partially received message
+    pub fn reset(&mut self) {
+        self.status = None;
+        self.sysex = false;
+        self.len = 0;
+    }
+
+    /// Feeds a received byte, returns the packets it completed
+    pub fn feed(&mut self, byte: u8) -> Packets {
+        match byte {
+            // undefined real-time
+            0xf9 | 0xfd => Packets([None, None]),
+            // real-time, may appear anywhere without affecting the current message
+            0xf8..=0xff => Packets([
+                Some(EventPacket::new(self.cable, cin::SINGLE_BYTE, [byte, 0, 0])),
+                None,
+            ]),
+            0x00..=0x7f => Packets([self.data_byte(byte), None]),
+            _ => {
+                // any other status byte terminates SysEx, and clears running status
+                let sysex = if self.sysex {
+                    self.sysex = false;
+                    Some(self.sysex_end())
+                } else {
+                    None
+                };
+                Packets([sysex, self.status_byte(byte)])
+            }
+        }
+    }
+
+    fn status_byte(&mut self, byte: u8) -> Option<EventPacket> {
+        self.len = 0;
+        self.status = None;
+        match byte {
+            SYSEX_START => {
+                self.sysex = true;
+                self.data[0] = byte;
+                self.len = 1;
+            }
+            0xf6 => {
+                return Some(EventPacket::new(
+                    self.cable,
+                    cin::SYSTEM_COMMON_1,
+                    [byte, 0, 0],
+                ))
+            }
+            0x80..=0xef | 0xf1..=0xf3 => self.status = Some(byte),
+            // undefined system common 0xF4, 0xF5 and EOX
+            _ => {}
+        }
+        None
+    }
+
+    fn data_byte(&mut self, byte: u8) -> Option<EventPacket> {
+        if self.sysex {
+            self.data[self.len] = byte;
+            self.len += 1;
+            if self.len < 3 {
+                return None;
+            }
+            self.len = 0;
+            return Some(EventPacket::new(self.cable, cin::SYSEX_START, self.data));
+        }
+
+        let status = self.status?;
+        self.data[1 + self.len] = byte;
+        self.len += 1;
+        let len = data_len(status);
+        if self.len < len {
+            return None;
+        }
+
+        self.len = 0;
+        if status >= 0xf0 {
+            // no running status for system common messages
+            self.status = None;
+        }
+        // unused bytes are 0, not left over from an earlier message
+        let mut data = [status, 0, 0];
+        data[1..=len].copy_from_slice(&self.data[1..=len]);
+        Some(EventPacket::new(self.cable, cin::from_status(status), data))
+    }
+
+    /// Last packet of a SysEx message, the pending bytes followed by 0xF7
+    fn sysex_end(&mut self) -> EventPacket {
+        let mut data = [0; 3];
+        data[..self.len].copy_from_slice(&self.data[..self.len]);
+        data[self.len] = SYSEX_END;
+        let cin = match self.len {
+            0 => cin::SYSEX_END_1,
+            1 => cin::SYSEX_END_2,
+            _ => cin::SYSEX_END_3,
+        };
+        self.len = 0;
+        EventPacket::new(self.cable, cin, data)
+    }
+}
+
+/// Number of data bytes following `status`
+fn data_len(status: u8) -> usize {
+    match status {
+        0xc0..=0xdf | 0xf1 | 0xf3 => 1,
+        0x80..=0xef | 0xf2 => 2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(parser: &mut PacketParser, bytes: &[u8]) -> [Option<EventPacket>; 4] {
+        let mut packets = [None; 4];
+        let mut i = 0;
+        for &byte in bytes {
+            for packet in parser.feed(byte) {
+                packets[i] = Some(packet);
+                i += 1;
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn unused_bytes_are_zero() {
+        let mut parser = PacketParser::new(0);
+        let packets = feed(&mut parser, &[0x90, 60, 100, 0xc0, 5, 0xf3, 2]);
+        assert_eq!(packets[0], Some(EventPacket::new(0, 0x9, [0x90, 60, 100])));
+        assert_eq!(packets[1], Some(EventPacket([0x0c, 0xc0, 5, 0])));
+        assert_eq!(packets[2], Some(EventPacket::new(0, 0x2, [0xf3, 2, 0])));
+        assert_eq!(packets[3], None);
+    }
+
+    #[test]
+    fn running_status_and_realtime() {
+        let mut parser = PacketParser::new(1);
+        let packets = feed(&mut parser, &[0xb0, 7, 0xf8, 100, 8, 50]);
+        assert_eq!(packets[0], Some(EventPacket::new(1, 0xf, [0xf8, 0, 0])));
+        assert_eq!(packets[1], Some(EventPacket::new(1, 0xb, [0xb0, 7, 100])));
+        assert_eq!(packets[2], Some(EventPacket::new(1, 0xb, [0xb0, 8, 50])));
+        assert_eq!(packets[3], None);
+    }
+
+    #[test]
+    fn interrupted_sysex() {
+        let mut parser = PacketParser::new(0);
+        let packets = feed(&mut parser, &[0xf0, 0x7e, 0x7f, 0x06, 0x90, 60, 1]);
+        assert_eq!(
+            packets[0],
+            Some(EventPacket::new(0, 0x4, [0xf0, 0x7e, 0x7f]))
+        );
+        assert_eq!(packets[1], Some(EventPacket::new(0, 0x6, [0x06, 0xf7, 0])));
+        assert_eq!(packets[2], Some(EventPacket::new(0, 0x9, [0x90, 60, 1])));
+        assert_eq!(packets[3], None);
+    }
+
+    #[test]
+    fn stray_eox_clears_running_status() {
+        let mut parser = PacketParser::new(0);
+        let packets = feed(&mut parser, &[0x90, 60, 100, 0xf7, 62, 100, 0x80, 60, 0]);
+        assert_eq!(packets[0], Some(EventPacket::new(0, 0x9, [0x90, 60, 100])));
+        assert_eq!(packets[1], Some(EventPacket::new(0, 0x8, [0x80, 60, 0])));
+        assert_eq!(packets[2], None);
+    }
+}
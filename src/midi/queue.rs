@@ -0,0 +1,154 @@
+//! Transmit queue of the USB-MIDI class
+//!
+//! Outgoing event packets wait in a `TxQueue` until the IN endpoint is free. When
+//! the host does not keep up the queue runs full, and its `OverflowPolicy` decides
+//! which packets are dropped.
+
+use super::{cin, rpn, sysex::SYSEX_END, EventPacket, TX_QUEUE_LEN};
+use heapless::Deque;
+use usb_device::{Result, UsbError};
+
+/// What to do with outgoing packets when the transmit queue is full
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum OverflowPolicy {
+    /// Drop the packet being sent (default)
+    DropNewest,
+    /// Drop the oldest queued packet to make room
+    ///
+    /// Queued SysEx is kept whole, dropping part of it would send a corrupt message.
+    /// The oldest packet of anything else is dropped instead, the new packet if the
+    /// queue only holds SysEx.
+    DropOldest,
+    /// Continuous controller values (control change, pitch bend, key and channel
+    /// pressure) replace a queued value for the same channel/controller, so only the
+    /// latest value is sent. The controllers used for (N)RPN are not coalesced.
+    /// Anything else is dropped when the queue is full.
+    Coalesce,
+}
+
+/// Queue of `TX_QUEUE_LEN` event packets waiting for transmission
+pub struct TxQueue {
+    packets: Deque<EventPacket, TX_QUEUE_LEN>,
+    policy: OverflowPolicy,
+    dropped: u32,
+}
+
+impl TxQueue {
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        TxQueue {
+            packets: Deque::new(),
+            policy,
+            dropped: 0,
+        }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
+    /// Queues `packet`, if the queue is full a packet is dropped according to the
+    /// `OverflowPolicy`, failing with `BufferOverflow` if it was this one
+    pub fn push(&mut self, packet: EventPacket) -> Result<()> {
+        if self.policy == OverflowPolicy::Coalesce {
+            if let Some(queued) = self.packets.iter_mut().find(|q| coalesces(q, &packet)) {
+                *queued = packet;
+                return Ok(());
+            }
+        }
+
+        if self.packets.is_full() {
+            self.dropped = self.dropped.wrapping_add(1);
+            let oldest = match self.policy {
+                OverflowPolicy::DropOldest => self.packets.iter().position(|p| !is_sysex(p)),
+                OverflowPolicy::DropNewest | OverflowPolicy::Coalesce => None,
+            };
+            match oldest {
+                Some(index) => self.remove(index),
+                None => return Err(UsbError::BufferOverflow),
+            }
+        }
+        self.packets.push_back(packet).ok();
+        Ok(())
+    }
+
+    /// Takes the oldest packet
+    pub fn pop(&mut self) -> Option<EventPacket> {
+        self.packets.pop_front()
+    }
+
+    /// The queued packets, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &EventPacket> {
+        self.packets.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Number of packets that can be queued without dropping any
+    pub fn room(&self) -> usize {
+        TX_QUEUE_LEN - self.packets.len()
+    }
+
+    /// Number of packets dropped because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    // Removes the packet at `index`, keeping the order of the others
+    fn remove(&mut self, index: usize) {
+        for i in 0..self.packets.len() {
+            if let Some(packet) = self.packets.pop_front() {
+                if i != index {
+                    self.packets.push_back(packet).ok();
+                }
+            }
+        }
+    }
+}
+
+/// True if `packet` carries (part of) a SysEx message
+fn is_sysex(packet: &EventPacket) -> bool {
+    match packet.cin() {
+        cin::SYSEX_START | cin::SYSEX_END_2 | cin::SYSEX_END_3 => true,
+        // or a single byte system common message
+        cin::SYSEX_END_1 => packet.0[1] == SYSEX_END,
+        _ => false,
+    }
+}
+
+/// True if `new` carries a newer value of the same continuous controller as `queued`
+fn coalesces(queued: &EventPacket, new: &EventPacket) -> bool {
+    // cable, CIN and status (incl. channel) must match
+    if queued.0[..2] != new.0[..2] {
+        return false;
+    }
+    match new.cin() {
+        // (N)RPN sequences must be sent as they are
+        cin::CONTROL_CHANGE if is_parameter_control(new.0[2]) => false,
+        // per controller/key
+        cin::CONTROL_CHANGE | cin::POLY_KEY_PRESSURE => queued.0[2] == new.0[2],
+        cin::PITCH_BEND | cin::CHANNEL_PRESSURE => true,
+        _ => false,
+    }
+}
+
+/// Controllers used to select and set (N)RPN parameters
+fn is_parameter_control(control: u8) -> bool {
+    matches!(
+        control,
+        rpn::DATA_ENTRY_MSB | rpn::DATA_ENTRY_LSB | rpn::DATA_INCREMENT..=rpn::RPN_MSB
+    )
+}
@@ -0,0 +1,168 @@
+//! Registered and Non-Registered Parameter Numbers
+//!
+//! A parameter is selected with CC 101/100 (RPN MSB/LSB) or CC 99/98 (NRPN MSB/LSB)
+//! and set with data entry CC 6 (MSB) and CC 38 (LSB). Selecting the null RPN
+//! (0x3fff) afterwards keeps later data entry messages from changing it by accident.
+
+use super::message::MidiMessage;
+
+pub const NRPN_LSB: u8 = 98;
+pub const NRPN_MSB: u8 = 99;
+pub const RPN_LSB: u8 = 100;
+pub const RPN_MSB: u8 = 101;
+pub const DATA_ENTRY_MSB: u8 = 6;
+pub const DATA_ENTRY_LSB: u8 = 38;
+pub const DATA_INCREMENT: u8 = 96;
+pub const DATA_DECREMENT: u8 = 97;
+
+/// Deselects the current parameter
+pub const NULL: u16 = 0x3fff;
+
+// Registered parameters
+pub const PITCH_BEND_SENSITIVITY: u16 = 0x0000;
+pub const FINE_TUNING: u16 = 0x0001;
+pub const COARSE_TUNING: u16 = 0x0002;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ParameterKind {
+    Registered,
+    NonRegistered,
+}
+
+/// A parameter set on a channel, number and value are 14 bit
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct ParameterChange {
+    pub channel: u8,
+    pub kind: ParameterKind,
+    pub number: u16,
+    pub value: u16,
+}
+
+impl ParameterChange {
+    /// The control changes setting the parameter, followed by the null RPN
+    pub fn messages(&self) -> [MidiMessage; 6] {
+        let (msb, lsb) = match self.kind {
+            ParameterKind::Registered => (RPN_MSB, RPN_LSB),
+            ParameterKind::NonRegistered => (NRPN_MSB, NRPN_LSB),
+        };
+        let cc = |control, value: u16| MidiMessage::ControlChange {
+            channel: self.channel,
+            control,
+            value: value as u8 & 0x7f,
+        };
+        [
+            cc(msb, self.number >> 7),
+            cc(lsb, self.number),
+            cc(DATA_ENTRY_MSB, self.value >> 7),
+            cc(DATA_ENTRY_LSB, self.value),
+            cc(RPN_MSB, NULL >> 7),
+            cc(RPN_LSB, NULL),
+        ]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ChannelState {
+    kind: ParameterKind,
+    number: u16,
+    value: u16,
+}
+
+impl ChannelState {
+    const fn new() -> Self {
+        ChannelState {
+            kind: ParameterKind::Registered,
+            number: NULL,
+            value: 0,
+        }
+    }
+}
+
+/// Collects the control changes of incoming (N)RPN messages, per channel
+///
+/// Every completed change arrives as a single event. By default a value is complete
+/// with its data entry LSB, the MSB alone only sets the coarse part. Senders of 7 bit
+/// values, with data entry MSB only, need `with_msb_only`.
+pub struct ParameterTracker {
+    channels: [ChannelState; 16],
+    msb_only: bool,
+}
+
+impl ParameterTracker {
+    pub const fn new() -> Self {
+        ParameterTracker {
+            channels: [ChannelState::new(); 16],
+            msb_only: false,
+        }
+    }
+
+    /// Completes values with their data entry MSB and ignores data entry LSB
+    pub const fn with_msb_only(mut self, msb_only: bool) -> Self {
+        self.msb_only = msb_only;
+        self
+    }
+
+    /// Feeds a received message, returns the parameter change it completed
+    ///
+    /// An event is returned for data entry LSB (MSB with `with_msb_only`) and data
+    /// increment/decrement, as long as a parameter is selected. Selecting the
+    /// parameter itself does not produce an event.
+    pub fn feed(&mut self, msg: &MidiMessage) -> Option<ParameterChange> {
+        let (channel, control, value) = match *msg {
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => (channel & 0x0f, control, value as u16),
+            _ => return None,
+        };
+        let state = &mut self.channels[channel as usize];
+
+        match control {
+            RPN_MSB | NRPN_MSB | RPN_LSB | NRPN_LSB => {
+                let kind = match control {
+                    RPN_MSB | RPN_LSB => ParameterKind::Registered,
+                    _ => ParameterKind::NonRegistered,
+                };
+                if kind != state.kind {
+                    state.kind = kind;
+                    state.number = NULL;
+                }
+                state.number = match control {
+                    RPN_MSB | NRPN_MSB => value << 7 | (state.number & 0x7f),
+                    _ => (state.number & !0x7f) | value,
+                };
+                state.value = 0;
+                return None;
+            }
+            DATA_ENTRY_MSB => {
+                state.value = value << 7;
+                if !self.msb_only {
+                    // the LSB follows
+                    return None;
+                }
+            }
+            DATA_ENTRY_LSB if self.msb_only => return None,
+            DATA_ENTRY_LSB => state.value = (state.value & !0x7f) | value,
+            DATA_INCREMENT => state.value = (state.value + 1).min(0x3fff),
+            DATA_DECREMENT => state.value = state.value.saturating_sub(1),
+            _ => return None,
+        }
+
+        if state.number == NULL {
+            return None;
+        }
+        Some(ParameterChange {
+            channel,
+            kind: state.kind,
+            number: state.number,
+            value: state.value,
+        })
+    }
+}
+
+impl Default for ParameterTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
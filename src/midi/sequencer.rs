@@ -0,0 +1,259 @@
+//! Step sequencer
+//!
+//! Plays a pattern of up to `MAX_STEPS` steps on one channel. Each step has its own
+//! note, velocity, gate length and probability, and every other step can be delayed
+//! for swing.
+//!
+//! The sequencer keeps its own timeline in µs. Call `poll` with the current time
+//! until it returns `None`, then call it again at `next_due`, e.g. by scheduling a
+//! task on a monotonic timer. Note-offs are part of the timeline, so a note is never
+//! left hanging, not even when the sequencer is stopped.
+
+use super::message::MidiMessage;
+use super::timing::{first_due, is_due, Rng, StepTimer};
+use heapless::Vec;
+
+/// Maximum pattern length
+pub const MAX_STEPS: usize = 64;
+
+/// Notes that can sound at the same time, with gates longer than a step
+pub const MAX_VOICES: usize = 8;
+
+/// Swing range in percent, the share of a pair of steps taken by the first one
+pub const MIN_SWING: u8 = 50;
+pub const MAX_SWING: u8 = 75;
+
+/// A step of a pattern
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Step {
+    pub note: u8,
+    /// 0 for a rest
+    pub velocity: u8,
+    /// Length of the note in percent of a step, above 100 ties into the next steps
+    pub gate: u8,
+    /// Chance that the step is played, in percent
+    pub probability: u8,
+}
+
+impl Step {
+    pub const REST: Step = Step {
+        note: 0,
+        velocity: 0,
+        gate: 0,
+        probability: 0,
+    };
+
+    /// A note that is always played, for half a step
+    pub const fn note(note: u8, velocity: u8) -> Self {
+        Step {
+            note,
+            velocity,
+            gate: 50,
+            probability: 100,
+        }
+    }
+
+    pub const fn gate(self, gate: u8) -> Self {
+        Step { gate, ..self }
+    }
+
+    pub const fn probability(self, probability: u8) -> Self {
+        Step {
+            probability,
+            ..self
+        }
+    }
+
+    pub fn is_rest(&self) -> bool {
+        self.velocity == 0 || self.gate == 0 || self.probability == 0
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    channel: u8,
+    note: u8,
+    off_at: u32,
+}
+
+pub struct Sequencer {
+    steps: Vec<Step, MAX_STEPS>,
+    channel: u8,
+    timer: StepTimer,
+    running: bool,
+    // index of the next step in the pattern
+    index: usize,
+    voices: Vec<Voice, MAX_VOICES>,
+    // note-on following the note-off of a retriggered note
+    retrigger: Option<MidiMessage>,
+    rng: Rng,
+}
+
+impl Sequencer {
+    /// Sequencer playing sixteenth notes (4 steps per beat) on `channel`
+    pub fn new(channel: u8, bpm: u32) -> Self {
+        Sequencer {
+            steps: Vec::new(),
+            channel: channel & 0x0f,
+            timer: StepTimer::new(bpm, 4),
+            running: false,
+            index: 0,
+            voices: Vec::new(),
+            retrigger: None,
+            rng: Rng::new(),
+        }
+    }
+
+    /// Replaces the pattern, steps beyond `MAX_STEPS` are ignored
+    pub fn set_pattern(&mut self, steps: &[Step]) {
+        let len = steps.len().min(MAX_STEPS);
+        self.steps = Vec::from_slice(&steps[..len]).unwrap();
+        if self.index >= len {
+            self.index = 0;
+        }
+    }
+
+    pub fn pattern(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn step_mut(&mut self, index: usize) -> Option<&mut Step> {
+        self.steps.get_mut(index)
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Changes the channel from the next step on, sounding notes end on the old one
+    pub fn set_channel(&mut self, channel: u8) {
+        self.channel = channel & 0x0f;
+    }
+
+    pub fn bpm(&self) -> u32 {
+        self.timer.bpm()
+    }
+
+    /// Changes the tempo from the next step on, clamped to `MIN_BPM..=MAX_BPM`
+    pub fn set_bpm(&mut self, bpm: u32) {
+        self.timer.set_bpm(bpm);
+    }
+
+    /// Sets the step length, 4 steps per beat plays sixteenth notes
+    pub fn set_steps_per_beat(&mut self, steps_per_beat: u32) {
+        self.timer.set_steps_per_beat(steps_per_beat);
+    }
+
+    /// Sets the swing in percent, clamped to `MIN_SWING..=MAX_SWING`
+    ///
+    /// At 50 all steps are equally long, at 66 every other step starts a triplet late.
+    pub fn set_swing(&mut self, swing: u8) {
+        self.timer.set_swing(swing.clamp(MIN_SWING, MAX_SWING));
+    }
+
+    /// Seeds the random generator used for the step probabilities
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng.seed(seed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Index of the next step to be played
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    /// Starts playing from the first step at `now`
+    pub fn start(&mut self, now: u32) {
+        self.running = true;
+        self.index = 0;
+        self.timer.start(now);
+    }
+
+    /// Stops playing, the next `poll` ends all sounding notes
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.retrigger = None;
+    }
+
+    /// Returns the next message due at `now`, call until it returns `None`
+    pub fn poll(&mut self, now: u32) -> Option<MidiMessage> {
+        if let Some(msg) = self.retrigger.take() {
+            return Some(msg);
+        }
+
+        let running = self.running;
+        if let Some(i) = self
+            .voices
+            .iter()
+            .position(|voice| !running || is_due(voice.off_at, now))
+        {
+            let voice = self.voices.swap_remove(i);
+            return Some(MidiMessage::note_off(voice.channel, voice.note));
+        }
+
+        while self.running && !self.steps.is_empty() && is_due(self.timer.next_at(), now) {
+            let step = self.steps[self.index];
+            self.index = (self.index + 1) % self.steps.len();
+            let at = self.timer.advance();
+            if step.is_rest() || !self.rng.chance(step.probability) {
+                continue;
+            }
+
+            let off_at = at.wrapping_add(self.timer.step_us(step.gate as u32));
+            let on = MidiMessage::NoteOn {
+                channel: self.channel,
+                note: step.note & 0x7f,
+                velocity: step.velocity & 0x7f,
+            };
+            let voice = Voice {
+                channel: self.channel,
+                note: step.note & 0x7f,
+                off_at,
+            };
+            if let Some(i) = self
+                .voices
+                .iter()
+                .position(|v| v.channel == voice.channel && v.note == voice.note)
+            {
+                // end the previous note first, so the note-offs do not get mixed up
+                self.voices[i] = voice;
+                self.retrigger = Some(on);
+                return Some(MidiMessage::note_off(voice.channel, voice.note));
+            }
+            if self.voices.is_full() {
+                // steal the voice ending first
+                let (i, _) = self
+                    .voices
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, voice)| voice.off_at.wrapping_sub(now))
+                    .unwrap();
+                let stolen = core::mem::replace(&mut self.voices[i], voice);
+                self.retrigger = Some(on);
+                return Some(MidiMessage::note_off(stolen.channel, stolen.note));
+            }
+            self.voices.push(voice).ok();
+            return Some(on);
+        }
+        None
+    }
+
+    /// Time at which `poll` has something to do next, `None` when stopped and silent
+    pub fn next_due(&self, now: u32) -> Option<u32> {
+        let step = if self.running && !self.steps.is_empty() {
+            Some(self.timer.next_at())
+        } else {
+            None
+        };
+        if self.retrigger.is_some() || (!self.running && !self.voices.is_empty()) {
+            return Some(now);
+        }
+        first_due(
+            self.voices.iter().map(|voice| voice.off_at).chain(step),
+            now,
+        )
+    }
+}
@@ -0,0 +1,248 @@
+//! System Exclusive messages over USB-MIDI
+//!
+//! SysEx data is split into 3 byte chunks, each sent with CIN 0x4 (start or continue)
+//! except the last one, which is sent with CIN 0x5, 0x6 or 0x7 depending on the number
+//! of bytes left (1, 2 or 3) including the terminating 0xF7.
+//!
+//! The Universal SysEx Identity Request/Reply lets hosts discover the device, see
+//! `Identity`.
+
+use heapless::Vec;
+
+use super::packet::{cin, EventPacket};
+
+/// Start of a SysEx message
+pub const SYSEX_START: u8 = 0xf0;
+/// End of a SysEx message
+pub const SYSEX_END: u8 = 0xf7;
+
+/// Universal Non-Real Time SysEx, in place of a manufacturer ID
+pub const UNIVERSAL_NON_REALTIME: u8 = 0x7e;
+/// Device ID addressing all devices
+pub const ALL_CALL: u8 = 0x7f;
+
+/// General Information sub-ID, and the Identity Request/Reply sub-ID#2
+const GENERAL_INFORMATION: u8 = 0x06;
+const IDENTITY_REQUEST: u8 = 0x01;
+const IDENTITY_REPLY: u8 = 0x02;
+
+/// Length of an Identity Request
+pub const IDENTITY_REQUEST_LEN: usize = 6;
+/// Maximum length of an Identity Reply, with an extended manufacturer ID
+pub const IDENTITY_REPLY_LEN: usize = 17;
+
+/// Firmware version reported in the Identity Reply, the crate version as
+/// `[major, minor, patch, 0]`
+pub const FIRMWARE_VERSION: [u8; 4] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+    0,
+];
+
+/// Event packets of a SysEx buffer, see `packets`
+pub struct SysExPackets<'a> {
+    cable: u8,
+    chunks: core::slice::Chunks<'a, u8>,
+}
+
+/// Splits `data` into event packets on virtual `cable`
+///
+/// `data` is normally a complete message, from 0xF0 up to and including 0xF7. When
+/// streaming a long message in parts, all parts but the last must be a multiple of 3
+/// bytes long.
+pub fn packets(cable: u8, data: &[u8]) -> SysExPackets<'_> {
+    SysExPackets {
+        cable,
+        chunks: data.chunks(3),
+    }
+}
+
+impl Iterator for SysExPackets<'_> {
+    type Item = EventPacket;
+
+    fn next(&mut self) -> Option<EventPacket> {
+        let chunk = self.chunks.next()?;
+        let cin = match (chunk.last(), chunk.len()) {
+            (Some(&SYSEX_END), 1) => cin::SYSEX_END_1,
+            (Some(&SYSEX_END), 2) => cin::SYSEX_END_2,
+            (Some(&SYSEX_END), _) => cin::SYSEX_END_3,
+            _ => cin::SYSEX_START,
+        };
+        let mut midi = [0; 3];
+        midi[..chunk.len()].copy_from_slice(chunk);
+        Some(EventPacket::new(self.cable, cin, midi))
+    }
+}
+
+/// Returns true if `packet` carries (part of) a SysEx message
+pub fn is_sysex(packet: &EventPacket) -> bool {
+    match packet.cin() {
+        cin::SYSEX_START | cin::SYSEX_END_2 | cin::SYSEX_END_3 => true,
+        // CIN 0x5 is shared with single byte System Common messages
+        cin::SYSEX_END_1 => packet.0[1] == SYSEX_END,
+        _ => false,
+    }
+}
+
+/// MIDI manufacturer ID
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum ManufacturerId {
+    /// One byte ID
+    Short(u8),
+    /// Three byte ID, 0x00 followed by these two bytes
+    Extended(u8, u8),
+}
+
+impl ManufacturerId {
+    /// Reserved for non-commercial and educational use
+    pub const NON_COMMERCIAL: ManufacturerId = ManufacturerId::Short(0x7d);
+}
+
+/// Device identity, as reported in the Identity Reply
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Identity {
+    pub manufacturer: ManufacturerId,
+    /// Device family code, 14 bits
+    pub family: u16,
+    /// Device family member (model) code, 14 bits
+    pub model: u16,
+    /// Software revision level
+    pub version: [u8; 4],
+}
+
+impl Identity {
+    /// Identity with the crate version as the software revision, see `FIRMWARE_VERSION`
+    pub const fn new(manufacturer: ManufacturerId, family: u16, model: u16) -> Self {
+        Identity {
+            manufacturer,
+            family,
+            model,
+            version: FIRMWARE_VERSION,
+        }
+    }
+
+    /// The Identity Reply from `device_id`, `F0 7E <device> 06 02 <manufacturer>
+    /// <family> <model> <version> F7`, with the codes LSB first
+    pub fn reply(&self, device_id: u8) -> Vec<u8, IDENTITY_REPLY_LEN> {
+        let mut reply = Vec::new();
+        reply
+            .extend_from_slice(&[
+                SYSEX_START,
+                UNIVERSAL_NON_REALTIME,
+                device_id & 0x7f,
+                GENERAL_INFORMATION,
+                IDENTITY_REPLY,
+            ])
+            .ok();
+        match self.manufacturer {
+            ManufacturerId::Short(id) => reply.push(id & 0x7f).ok(),
+            ManufacturerId::Extended(id1, id2) => reply
+                .extend_from_slice(&[0x00, id1 & 0x7f, id2 & 0x7f])
+                .ok(),
+        };
+        for code in [self.family, self.model] {
+            reply
+                .extend_from_slice(&[(code & 0x7f) as u8, (code >> 7 & 0x7f) as u8])
+                .ok();
+        }
+        for byte in self.version {
+            reply.push(byte & 0x7f).ok();
+        }
+        reply.push(SYSEX_END).ok();
+        reply
+    }
+}
+
+/// Returns true if `data` is an Identity Request for `device_id`
+///
+/// Requests sent to `ALL_CALL` are for every device, a device using `ALL_CALL` as its
+/// own ID answers all requests.
+pub fn is_identity_request(data: &[u8], device_id: u8) -> bool {
+    match *data {
+        [SYSEX_START, UNIVERSAL_NON_REALTIME, device, GENERAL_INFORMATION, IDENTITY_REQUEST, SYSEX_END] => {
+            device == ALL_CALL || device_id == ALL_CALL || device == device_id
+        }
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum SysExError {
+    /// The message did not fit in the receive buffer, the rest of it is discarded
+    Overflow,
+}
+
+/// Reassembles incoming SysEx messages of at most `N` bytes (including 0xF0 and 0xF7)
+pub struct SysExReceiver<const N: usize> {
+    buf: Vec<u8, N>,
+    receiving: bool,
+    overflow: bool,
+}
+
+impl<const N: usize> SysExReceiver<N> {
+    pub const fn new() -> Self {
+        SysExReceiver {
+            buf: Vec::new(),
+            receiving: false,
+            overflow: false,
+        }
+    }
+
+    /// Feeds a received packet, non SysEx packets are ignored
+    ///
+    /// Returns the complete message once its last packet has been received. An
+    /// overflow is reported once per message, when the buffer runs full.
+    pub fn push(&mut self, packet: &EventPacket) -> Result<Option<&[u8]>, SysExError> {
+        if !is_sysex(packet) {
+            return Ok(None);
+        }
+
+        let data = packet.midi_bytes();
+        if data[0] == SYSEX_START {
+            self.buf.clear();
+            self.receiving = true;
+            self.overflow = false;
+        } else if !self.receiving {
+            // continuation of a message we never saw the start of
+            return Ok(None);
+        }
+
+        let end = packet.cin() != cin::SYSEX_START;
+        if end {
+            self.receiving = false;
+        }
+
+        if self.overflow {
+            return Ok(None);
+        }
+        if self.buf.extend_from_slice(data).is_err() {
+            self.overflow = true;
+            return Err(SysExError::Overflow);
+        }
+
+        if end {
+            Ok(Some(&self.buf))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<const N: usize> Default for SysExReceiver<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Parses a version number at compile time
+const fn parse_version(number: &str) -> u8 {
+    let bytes = number.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
@@ -0,0 +1,152 @@
+//! Timing and randomness shared by the clock, the sequencer and the arpeggiator
+//!
+//! Times are in µs that wrap around, like the ticks of a 32 bit monotonic timer.
+//! `StepTimer` computes the time of every step from an anchor step, so rounding errors
+//! do not accumulate.
+
+use super::clock::{MAX_BPM, MIN_BPM};
+
+pub(crate) const MICROS_PER_MINUTE: u64 = 60_000_000;
+
+/// Whether `at` is not in the future of `now`, for timestamps that wrap around
+pub(crate) fn is_due(at: u32, now: u32) -> bool {
+    now.wrapping_sub(at) < 1 << 31
+}
+
+/// The first of `times` to be due, those already due at `now` count as now
+pub(crate) fn first_due(times: impl Iterator<Item = u32>, now: u32) -> Option<u32> {
+    times.min_by_key(|at| {
+        if is_due(*at, now) {
+            0
+        } else {
+            at.wrapping_sub(now)
+        }
+    })
+}
+
+/// Times of a stream of steps at a tempo, optionally with swing
+pub(crate) struct StepTimer {
+    bpm: u32,
+    steps_per_beat: u32,
+    // share of a pair of steps taken by the first one, in percent
+    swing: u8,
+    // steps since the start, and when the next one is due
+    count: u32,
+    next_at: u32,
+    // timing is computed relative to a step, reset on tempo changes
+    anchor_count: u32,
+    anchor_at: u32,
+}
+
+impl StepTimer {
+    pub fn new(bpm: u32, steps_per_beat: u32) -> Self {
+        StepTimer {
+            bpm: bpm.clamp(MIN_BPM, MAX_BPM),
+            steps_per_beat: steps_per_beat.clamp(1, 24),
+            swing: 50,
+            count: 0,
+            next_at: 0,
+            anchor_count: 0,
+            anchor_at: 0,
+        }
+    }
+
+    pub fn bpm(&self) -> u32 {
+        self.bpm
+    }
+
+    /// Changes the tempo from the next step on, clamped to `MIN_BPM..=MAX_BPM`
+    pub fn set_bpm(&mut self, bpm: u32) {
+        self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+        self.reanchor();
+    }
+
+    /// Changes the step length from the next step on, clamped to `1..=24` per beat
+    pub fn set_steps_per_beat(&mut self, steps_per_beat: u32) {
+        self.steps_per_beat = steps_per_beat.clamp(1, 24);
+        self.reanchor();
+    }
+
+    /// Sets the share of a pair of steps taken by the first one, 50 for no swing
+    pub fn set_swing(&mut self, swing: u8) {
+        self.swing = swing;
+        self.reanchor();
+    }
+
+    /// Restarts with the first step due at `now`
+    pub fn start(&mut self, now: u32) {
+        self.count = 0;
+        self.next_at = now;
+        self.anchor_count = 0;
+        self.anchor_at = now;
+    }
+
+    /// Time of the next step
+    pub fn next_at(&self) -> u32 {
+        self.next_at
+    }
+
+    /// Takes the next step, returns its time
+    pub fn advance(&mut self) -> u32 {
+        let at = self.next_at;
+        self.count = self.count.wrapping_add(1);
+        let steps = self.count.wrapping_sub(self.anchor_count);
+        self.next_at = self.anchor_at.wrapping_add(self.offset_us(steps) as u32);
+        if steps >= 1 << 16 {
+            // the next step is exactly on time, keep the offsets small
+            self.reanchor();
+        }
+        at
+    }
+
+    /// Length of a step without swing, `percent` of it in µs
+    pub fn step_us(&self, percent: u32) -> u32 {
+        let step_us = MICROS_PER_MINUTE / (self.bpm * self.steps_per_beat) as u64;
+        (step_us * percent as u64 / 100) as u32
+    }
+
+    // Keeps the time of the next step, the new timing applies after it
+    fn reanchor(&mut self) {
+        self.anchor_count = self.count;
+        self.anchor_at = self.next_at;
+    }
+
+    // Time from the anchor to `steps` steps later, exact so rounding errors do not
+    // accumulate
+    fn offset_us(&self, steps: u32) -> u64 {
+        // swing delays the odd steps counted from the start
+        let first = if self.anchor_count % 2 == 0 {
+            self.swing
+        } else {
+            100 - self.swing
+        };
+        let percent = (steps / 2) as u64 * 200 + (steps % 2) as u64 * 2 * first as u64;
+        percent * MICROS_PER_MINUTE / (self.bpm * self.steps_per_beat * 100) as u64
+    }
+}
+
+/// xorshift32 random generator, for step probabilities and random notes
+pub(crate) struct Rng(u32);
+
+impl Rng {
+    pub const fn new() -> Self {
+        Rng(0x2545_f491)
+    }
+
+    /// Restarts the sequence from `seed`, 0 is taken as 1
+    pub fn seed(&mut self, seed: u32) {
+        self.0 = seed.max(1);
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// True with a chance of `percent`
+    pub fn chance(&mut self, percent: u8) -> bool {
+        percent >= 100 || self.next_u32() % 100 < percent as u32
+    }
+}
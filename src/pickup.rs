@@ -0,0 +1,94 @@
+//! Soft takeover
+//!
+//! After a parameter is changed on the host (with the mouse, by automation or another
+//! controller) the knob no longer matches it, and moving the knob makes the value jump.
+//! With `Pickup` a control only sends once it reaches or crosses the last value received
+//! from the host, from then on it follows the knob again.
+//!
+//! Values are in the units sent, e.g. 7 or 14 bit CC values.
+
+#[derive(Clone, Copy)]
+struct Slot {
+    // last value on the host, received or sent
+    host: Option<u16>,
+    // last position of the knob
+    knob: Option<u16>,
+    picked: bool,
+}
+
+/// Pickup state of `N` controls
+pub struct Pickup<const N: usize> {
+    window: u16,
+    slots: [Slot; N],
+}
+
+impl<const N: usize> Pickup<N> {
+    /// Host values within `window` of the knob count as reached, so late echoes of
+    /// the values sent do not lose the pickup
+    pub const fn new(window: u16) -> Self {
+        Pickup {
+            window,
+            slots: [Slot {
+                host: None,
+                knob: None,
+                picked: true,
+            }; N],
+        }
+    }
+
+    /// Last value of control `index` on the host
+    pub fn host_value(&self, index: usize) -> Option<u16> {
+        self.slots.get(index)?.host
+    }
+
+    /// Sets the value of control `index` on the host, as received from it
+    ///
+    /// The control waits to be picked up unless the knob is at the value.
+    pub fn set_host_value(&mut self, index: usize, value: u16) {
+        let window = self.window;
+        if let Some(slot) = self.slots.get_mut(index) {
+            slot.host = Some(value);
+            slot.picked = matches!(slot.knob, Some(knob) if knob.abs_diff(value) <= window);
+        }
+    }
+
+    /// Feeds a new position of control `index`, returns whether it is picked up and
+    /// the value should be sent
+    pub fn update(&mut self, index: usize, value: u16) -> bool {
+        let window = self.window;
+        let slot = match self.slots.get_mut(index) {
+            Some(slot) => slot,
+            None => return true,
+        };
+        let previous = slot.knob.replace(value);
+        if !slot.picked {
+            slot.picked = match (slot.host, previous) {
+                (None, _) => true,
+                (Some(host), previous) => {
+                    let previous = previous.unwrap_or(value);
+                    let crossed = (previous.min(value)..=previous.max(value)).contains(&host);
+                    crossed || host.abs_diff(value) <= window
+                }
+            };
+        }
+        if slot.picked {
+            slot.host = Some(value);
+        }
+        slot.picked
+    }
+
+    pub fn is_picked_up(&self, index: usize) -> bool {
+        self.slots.get(index).map(|slot| slot.picked).unwrap_or(true)
+    }
+
+    /// Whether any control waits to be picked up
+    pub fn is_waiting(&self) -> bool {
+        self.slots.iter().any(|slot| !slot.picked)
+    }
+}
+
+impl<const N: usize> Default for Pickup<N> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
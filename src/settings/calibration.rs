@@ -0,0 +1,83 @@
+//! Pot calibration
+//!
+//! Cheap pots never reach the ends of the ADC range. While calibrating, the lowest and
+//! highest readings of every control are tracked: start calibrating, turn every pot
+//! from end to end, then `finish` sets them as the ranges of the controls. Controls
+//! turned less than `MIN_RANGE` keep their range.
+
+use super::{Settings, MAX_CONTROLS};
+
+/// Smallest range of readings taken as a calibration
+pub const MIN_RANGE: u16 = 1024;
+
+// the range is narrowed by this much, so the ends are reached despite noise
+const MARGIN: u16 = 8;
+
+pub struct Calibration {
+    active: bool,
+    ranges: [Option<(u16, u16)>; MAX_CONTROLS],
+}
+
+impl Calibration {
+    pub const fn new() -> Self {
+        Calibration {
+            active: false,
+            ranges: [None; MAX_CONTROLS],
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Starts calibrating, forgetting the readings of an earlier calibration
+    pub fn start(&mut self) {
+        self.active = true;
+        self.ranges = [None; MAX_CONTROLS];
+    }
+
+    pub fn cancel(&mut self) {
+        self.active = false;
+    }
+
+    /// Tracks a 12 bit reading of control `index`
+    pub fn feed(&mut self, index: usize, raw: u16) {
+        if !self.active {
+            return;
+        }
+        if let Some(range) = self.ranges.get_mut(index) {
+            *range = match *range {
+                Some((min, max)) => Some((min.min(raw), max.max(raw))),
+                None => Some((raw, raw)),
+            };
+        }
+    }
+
+    /// Sets the ranges of the controls turned far enough, and ends calibrating
+    ///
+    /// Returns the number of controls calibrated.
+    pub fn finish(&mut self, settings: &mut Settings) -> usize {
+        if !self.active {
+            return 0;
+        }
+        self.active = false;
+
+        let mut calibrated = 0;
+        for (control, range) in settings.controls.iter_mut().zip(self.ranges) {
+            if let Some((min, max)) = range {
+                if max - min >= MIN_RANGE {
+                    control.min = min + MARGIN;
+                    control.max = max - MARGIN;
+                    calibrated += 1;
+                }
+            }
+        }
+        calibrated
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,51 @@
+//! Settings storage in flash
+//!
+//! The settings are kept in the last 1K page of the 64K flash, which `memory.x`
+//! leaves out of the program area. The record starts with a magic number and a
+//! checksum, so erased or corrupted flash reads as no settings.
+
+use stm32f1xx_hal::flash::{self, FlashSize, SectorSize};
+
+use super::{Settings, ENCODED_LEN};
+
+/// Offset of the settings page from the start of flash
+pub const OFFSET: u32 = 63 * 1024;
+const PAGE_SIZE: usize = 1024;
+
+const MAGIC: [u8; 2] = *b"MC";
+const HEADER_LEN: usize = 4;
+// flash is written in half words
+const RECORD_LEN: usize = (HEADER_LEN + ENCODED_LEN + 1) & !1;
+
+/// Reads the stored settings, `None` if there are none or they are not valid
+pub fn load(parts: &mut flash::Parts) -> Option<Settings> {
+    let writer = parts.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+    let record = writer.read(OFFSET, RECORD_LEN).ok()?;
+    let (header, data) = record.split_at(HEADER_LEN);
+    let data = &data[..ENCODED_LEN];
+    if header[..2] != MAGIC || header[2] as usize != ENCODED_LEN || header[3] != checksum(data) {
+        return None;
+    }
+    Settings::decode(data)
+}
+
+/// Stores `settings`, erasing the page first
+///
+/// The CPU stalls while the page is erased, for about 20 ms.
+pub fn store(parts: &mut flash::Parts, settings: &Settings) -> Result<(), flash::Error> {
+    let data = settings.encode();
+    let mut record = [0xff; RECORD_LEN];
+    record[..2].copy_from_slice(&MAGIC);
+    record[2] = ENCODED_LEN as u8;
+    record[3] = checksum(&data);
+    record[HEADER_LEN..HEADER_LEN + ENCODED_LEN].copy_from_slice(&data);
+
+    let mut writer = parts.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+    writer.erase(OFFSET, PAGE_SIZE)?;
+    writer.write(OFFSET, &record)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0x5a, |sum, byte| sum.rotate_left(1) ^ byte)
+}
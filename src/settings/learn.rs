@@ -0,0 +1,92 @@
+//! MIDI learn
+//!
+//! Binds a control to a channel and CC number chosen on the host: start learning (from
+//! a button or over SysEx), move the control, then send a CC from the host. Moving
+//! another control before the CC arrives picks that one instead. CCs above the limit set
+//! with `with_max_cc` are ignored.
+
+use super::{Settings, MAX_CC};
+use crate::midi::MidiMessage;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum LearnState {
+    Off,
+    /// Waiting for a control to be moved
+    Control,
+    /// Waiting for a CC from the host to bind the control to
+    Cc {
+        control: usize,
+    },
+}
+
+pub struct Learn {
+    state: LearnState,
+    max_cc: u8,
+}
+
+impl Learn {
+    pub const fn new() -> Self {
+        Learn {
+            state: LearnState::Off,
+            max_cc: MAX_CC,
+        }
+    }
+
+    /// Only binds CC numbers up to `max_cc`, e.g. `MAX_CC_14BIT` for 14 bit values
+    pub const fn with_max_cc(mut self, max_cc: u8) -> Self {
+        self.max_cc = max_cc;
+        self
+    }
+
+    pub fn state(&self) -> LearnState {
+        self.state
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state != LearnState::Off
+    }
+
+    /// Starts learning, forgetting a control moved earlier
+    pub fn start(&mut self) {
+        self.state = LearnState::Control;
+    }
+
+    pub fn cancel(&mut self) {
+        self.state = LearnState::Off;
+    }
+
+    /// Selects the control to bind, call when control `index` is moved
+    pub fn control_moved(&mut self, index: usize) {
+        if self.is_active() {
+            self.state = LearnState::Cc { control: index };
+        }
+    }
+
+    /// Binds the selected control to a CC received from the host, and ends learning
+    ///
+    /// Returns the index of the control if `settings` were changed.
+    pub fn feed(&mut self, settings: &mut Settings, msg: &MidiMessage) -> Option<usize> {
+        let index = match self.state {
+            LearnState::Cc { control } => control,
+            _ => return None,
+        };
+        let (channel, cc) = match *msg {
+            MidiMessage::ControlChange {
+                channel, control, ..
+            } if control <= self.max_cc.min(MAX_CC) => (channel, control),
+            _ => return None,
+        };
+
+        let control = settings.controls.get_mut(index)?;
+        control.channel = Some(channel);
+        control.cc = cc;
+        self.state = LearnState::Off;
+        Some(index)
+    }
+}
+
+impl Default for Learn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
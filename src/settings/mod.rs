@@ -0,0 +1,381 @@
+//! Controller settings
+//!
+//! The MIDI channel, pickup mode, the table of the custom response curve and, per
+//! analog control, the channel and CC number it is bound to, response curve, change
+//! threshold and calibrated range. Settings are changed by the host with the SysEx
+//! parameter protocol in `protocol`, by MIDI learn in `learn` or by calibration in
+//! `calibration`, and kept in the last page of flash by `flash`.
+//!
+//! Settings are encoded as 7 bit bytes, so the same encoding is used for SysEx dumps
+//! and in flash.
+
+use heapless::Vec;
+
+pub mod calibration;
+#[cfg(target_os = "none")]
+pub mod flash;
+pub mod learn;
+pub mod protocol;
+
+/// Analog controls, the ADC1 inputs of the Blue Pill (PA0..PA7, PB0, PB1)
+pub const MAX_CONTROLS: usize = 10;
+
+/// Version of the encoding, settings of another version are not restored
+pub const FORMAT_VERSION: u8 = 4;
+
+/// Points of the custom curve table, evenly spaced over the 12 bit reading
+pub const LUT_LEN: usize = 17;
+
+/// Largest 12 bit reading
+pub const MAX_RAW: u16 = 4095;
+
+const CONTROL_LEN: usize = 9;
+/// Length of the encoded settings
+pub const ENCODED_LEN: usize = 3 + MAX_CONTROLS * CONTROL_LEN + 2 * LUT_LEN;
+
+/// Highest CC number for a control, 120..=127 are channel mode messages
+pub const MAX_CC: u8 = 119;
+
+/// Highest CC number for a control sending 14 bit values, as `MidiClass::ctrl_14bit`
+/// takes them
+pub const MAX_CC_14BIT: u8 = crate::midi::MAX_CC_14BIT;
+
+/// Largest 14 bit value
+const MAX_VALUE: u16 = 0x3fff;
+
+/// Encoding of a control channel that follows the global channel
+pub const GLOBAL_CHANNEL: u8 = 0x7f;
+
+/// Response curve, maps the 12 bit ADC reading to the control value
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Curve {
+    Linear,
+    /// Rises fast at the start, for exponential (audio taper) pots
+    Log,
+    /// Rises slowly at the start
+    Exp,
+    /// Slow at both ends, fast in the middle
+    SCurve,
+    /// The table in `Settings::lut`
+    Custom,
+}
+
+impl Curve {
+    pub fn from_u8(curve: u8) -> Option<Self> {
+        match curve {
+            0 => Some(Curve::Linear),
+            1 => Some(Curve::Log),
+            2 => Some(Curve::Exp),
+            3 => Some(Curve::SCurve),
+            4 => Some(Curve::Custom),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Applies the curve to a 12 bit reading, `lut` is the table of `Curve::Custom`
+    pub fn apply(self, raw: u16, lut: &[u16; LUT_LEN]) -> u16 {
+        const MAX: u32 = MAX_RAW as u32;
+        let x = raw.min(MAX_RAW) as u32;
+        let y = match self {
+            Curve::Linear => x,
+            Curve::Log => MAX - (MAX - x) * (MAX - x) / MAX,
+            Curve::Exp => x * x / MAX,
+            // smoothstep, 3x^2 - 2x^3
+            Curve::SCurve => {
+                (x as u64 * x as u64 * (3 * MAX - 2 * x) as u64 / (MAX * MAX) as u64) as u32
+            }
+            Curve::Custom => {
+                // linear interpolation between the points
+                let point = |i: usize| lut[i].min(MAX_RAW) as u32;
+                let scaled = x * (LUT_LEN as u32 - 1);
+                let (i, frac) = ((scaled / MAX) as usize, scaled % MAX);
+                if i + 1 >= LUT_LEN {
+                    point(LUT_LEN - 1)
+                } else {
+                    let (y0, y1) = (point(i), point(i + 1));
+                    if y1 >= y0 {
+                        y0 + (y1 - y0) * frac / MAX
+                    } else {
+                        y0 - (y0 - y1) * frac / MAX
+                    }
+                }
+            }
+        };
+        y as u16
+    }
+}
+
+/// Settings of an analog control
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Control {
+    /// MIDI channel, `None` for the global one
+    pub channel: Option<u8>,
+    /// CC number, `0..=MAX_CC`
+    pub cc: u8,
+    pub curve: Curve,
+    /// Hysteresis of the control value, smaller changes are taken as noise, 14 bits
+    pub threshold: u16,
+    /// Lowest 12 bit reading of the pot, see `calibration`
+    pub min: u16,
+    /// Highest 12 bit reading of the pot, above `min`
+    pub max: u16,
+}
+
+impl Control {
+    /// Scales a 12 bit reading from the calibrated range to the full range
+    ///
+    /// Readings are passed on as they are if the range is empty, `min >= max`.
+    pub fn calibrate(&self, raw: u16) -> u16 {
+        if self.min >= self.max {
+            return raw;
+        }
+        let range = (self.max - self.min) as u32;
+        let x = raw.clamp(self.min, self.max) - self.min;
+        ((x as u32 * MAX_RAW as u32 + range / 2) / range) as u16
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct Settings {
+    /// MIDI channel, `0..=15`
+    pub channel: u8,
+    /// Soft takeover, controls only send once they reach the value on the host
+    pub pickup: bool,
+    pub controls: [Control; MAX_CONTROLS],
+    /// Custom curve, `LUT_LEN` points of `0..=MAX_RAW`
+    pub lut: [u16; LUT_LEN],
+}
+
+impl Default for Settings {
+    /// Channel 0, no pickup, the controls on CC 1, 2, 3 ... with a linear curve,
+    /// threshold 1 and the full range, and a linear custom curve
+    fn default() -> Self {
+        let mut controls = [Control {
+            channel: None,
+            cc: 0,
+            curve: Curve::Linear,
+            threshold: 1,
+            min: 0,
+            max: MAX_RAW,
+        }; MAX_CONTROLS];
+        for (i, control) in controls.iter_mut().enumerate() {
+            control.cc = i as u8 + 1;
+        }
+        let mut lut = [0; LUT_LEN];
+        for (i, point) in lut.iter_mut().enumerate() {
+            *point = (i * MAX_RAW as usize / (LUT_LEN - 1)) as u16;
+        }
+        Settings {
+            channel: 0,
+            pickup: false,
+            controls,
+            lut,
+        }
+    }
+}
+
+impl Settings {
+    /// Channel control `index` sends on
+    pub fn control_channel(&self, index: usize) -> u8 {
+        self.controls
+            .get(index)
+            .and_then(|control| control.channel)
+            .unwrap_or(self.channel)
+    }
+
+    /// Control value of a 12 bit reading of control `index`, calibrated and with the
+    /// response curve applied
+    pub fn response(&self, index: usize, raw: u16) -> u16 {
+        let control = &self.controls[index];
+        control.curve.apply(control.calibrate(raw), &self.lut)
+    }
+
+    /// Encodes the settings as `ENCODED_LEN` 7 bit bytes
+    pub fn encode(&self) -> Vec<u8, ENCODED_LEN> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[FORMAT_VERSION, self.channel, self.pickup as u8])
+            .ok();
+        for control in &self.controls {
+            data.extend_from_slice(&[
+                control.channel.unwrap_or(GLOBAL_CHANNEL),
+                control.cc,
+                control.curve.to_u8(),
+                (control.threshold & 0x7f) as u8,
+                (control.threshold >> 7 & 0x7f) as u8,
+                (control.min & 0x7f) as u8,
+                (control.min >> 7 & 0x7f) as u8,
+                (control.max & 0x7f) as u8,
+                (control.max >> 7 & 0x7f) as u8,
+            ])
+            .ok();
+        }
+        for point in &self.lut {
+            data.extend_from_slice(&[(point & 0x7f) as u8, (point >> 7 & 0x7f) as u8])
+                .ok();
+        }
+        data
+    }
+
+    /// Decodes settings encoded by `encode`, `None` if `data` is not valid
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != ENCODED_LEN || data[0] != FORMAT_VERSION {
+            return None;
+        }
+        let channel = valid_channel(data[1])?;
+        let pickup = flag(data[2])?;
+        if data.iter().any(|byte| *byte > 0x7f) {
+            return None;
+        }
+        let (control_data, lut_data) = data[3..].split_at(MAX_CONTROLS * CONTROL_LEN);
+        let mut controls = Settings::default().controls;
+        for (control, data) in controls
+            .iter_mut()
+            .zip(control_data.chunks_exact(CONTROL_LEN))
+        {
+            control.channel = control_channel(data[0])?;
+            control.cc = valid_cc(data[1])?;
+            control.curve = Curve::from_u8(data[2])?;
+            control.threshold = data[3] as u16 | (data[4] as u16) << 7;
+            control.min = data[5] as u16 | (data[6] as u16) << 7;
+            control.max = data[7] as u16 | (data[8] as u16) << 7;
+            valid_range(control.min, control.max)?;
+        }
+        let mut lut = [0; LUT_LEN];
+        for (point, data) in lut.iter_mut().zip(lut_data.chunks_exact(2)) {
+            *point = data[0] as u16 | (data[1] as u16) << 7;
+            if *point > MAX_RAW {
+                return None;
+            }
+        }
+        Some(Settings {
+            channel,
+            pickup,
+            controls,
+            lut,
+        })
+    }
+
+    /// Reads a parameter, `None` if there is no control (or point) `index`
+    pub fn get(&self, param: Param, index: usize) -> Option<u16> {
+        let value = match param {
+            Param::Channel => self.channel as u16,
+            Param::Pickup => self.pickup as u16,
+            Param::Cc => self.controls.get(index)?.cc as u16,
+            Param::Curve => self.controls.get(index)?.curve.to_u8() as u16,
+            Param::Threshold => self.controls.get(index)?.threshold,
+            Param::ControlChannel => {
+                let channel = self.controls.get(index)?.channel;
+                channel.unwrap_or(GLOBAL_CHANNEL) as u16
+            }
+            Param::Min => self.controls.get(index)?.min,
+            Param::Max => self.controls.get(index)?.max,
+            Param::Lut => *self.lut.get(index)?,
+        };
+        Some(value)
+    }
+
+    /// Whether all controls are bound to CC numbers up to `max_cc`
+    pub fn cc_within(&self, max_cc: u8) -> bool {
+        self.controls.iter().all(|control| control.cc <= max_cc)
+    }
+
+    /// Changes a parameter, `None` if there is no control (or point) `index` or `value`
+    /// is out of range
+    pub fn set(&mut self, param: Param, index: usize, value: u16) -> Option<()> {
+        let byte = u8::try_from(value).ok();
+        match param {
+            Param::Channel => self.channel = valid_channel(byte?)?,
+            Param::Pickup => self.pickup = flag(byte?)?,
+            Param::Cc => self.controls.get_mut(index)?.cc = valid_cc(byte?)?,
+            Param::Curve => self.controls.get_mut(index)?.curve = Curve::from_u8(byte?)?,
+            Param::Threshold if value <= MAX_VALUE => {
+                self.controls.get_mut(index)?.threshold = value
+            }
+            Param::Threshold => return None,
+            Param::ControlChannel => {
+                self.controls.get_mut(index)?.channel = control_channel(byte?)?
+            }
+            Param::Min => {
+                let control = self.controls.get_mut(index)?;
+                valid_range(value, control.max)?;
+                control.min = value;
+            }
+            Param::Max => {
+                let control = self.controls.get_mut(index)?;
+                valid_range(control.min, value)?;
+                control.max = value;
+            }
+            Param::Lut if value <= MAX_RAW => *self.lut.get_mut(index)? = value,
+            Param::Lut => return None,
+        }
+        Some(())
+    }
+}
+
+/// Parameters, `Channel` and `Pickup` are global, `Lut` per point of the custom curve and the others
+/// per control
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum Param {
+    Channel,
+    Cc,
+    Curve,
+    Threshold,
+    /// The channel of a control, `GLOBAL_CHANNEL` to follow the global one
+    ControlChannel,
+    /// Lowest reading of the calibrated range, 12 bits
+    Min,
+    /// Highest reading of the calibrated range, 12 bits
+    Max,
+    /// A point of the custom curve, 12 bits
+    Lut,
+    /// Pickup mode, 0 or 1
+    Pickup,
+}
+
+impl Param {
+    pub fn from_u8(param: u8) -> Option<Self> {
+        match param {
+            0 => Some(Param::Channel),
+            1 => Some(Param::Cc),
+            2 => Some(Param::Curve),
+            3 => Some(Param::Threshold),
+            4 => Some(Param::ControlChannel),
+            5 => Some(Param::Min),
+            6 => Some(Param::Max),
+            7 => Some(Param::Lut),
+            8 => Some(Param::Pickup),
+            _ => None,
+        }
+    }
+}
+
+fn valid_channel(channel: u8) -> Option<u8> {
+    (channel <= 0x0f).then_some(channel)
+}
+
+fn control_channel(channel: u8) -> Option<Option<u8>> {
+    match channel {
+        GLOBAL_CHANNEL => Some(None),
+        channel => valid_channel(channel).map(Some),
+    }
+}
+
+fn valid_cc(cc: u8) -> Option<u8> {
+    (cc <= MAX_CC).then_some(cc)
+}
+
+fn flag(flag: u8) -> Option<bool> {
+    match flag {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+fn valid_range(min: u16, max: u16) -> Option<()> {
+    (min < max && max <= MAX_RAW).then_some(())
+}
@@ -0,0 +1,195 @@
+//! SysEx parameter protocol
+//!
+//! Requests and replies are SysEx messages with the non-commercial manufacturer ID:
+//!
+//! ```text
+//! F0 7D <device> <command> <data> F7
+//! ```
+//!
+//! | command        | request data                    | reply                  |
+//! |----------------|---------------------------------|------------------------|
+//! | `01` get       | `<param> <index>`               | value                  |
+//! | `02` set       | `<param> <index> <lsb> <msb>`   | value (the new one)    |
+//! | `03` dump      |                                 | `13 <settings>`        |
+//! | `04` restore   | `<settings>` from a dump        | `14 04`                |
+//! | `05` defaults  |                                 | `14 05`                |
+//! | `06` learn     |                                 | `14 06`                |
+//! | `07` cancel    |                                 | `14 07`                |
+//! | `08` calibrate |                                 | `14 08`                |
+//! | `09` finish    |                                 | `14 09`                |
+//!
+//! A value reply is `11 <param> <index> <lsb> <msb>`, with the 14 bit value in two 7 bit
+//! bytes. Parameters are channel `00`, CC `01`, curve `02` (linear, log, exp, S-curve or
+//! custom), threshold `03`, control channel `04` (`7F` to follow the global channel),
+//! calibrated min `05` and max `06`, custom curve point `07` and pickup mode `08` (0 or
+//! 1). `<index>` selects the control, or the point of the custom curve (ignored for the
+//! channel and pickup mode). Failed requests are answered with `7F <command> <error>`,
+//! see `Error`.
+//!
+//! CC numbers above the `max_cc` given to `handle` are refused, `MAX_CC_14BIT` when
+//! sending 14 bit values.
+//!
+//! Learn and cancel start and cancel MIDI learn, see `learn`. Calibrate and finish
+//! start and finish calibration, see `calibration`.
+
+use heapless::Vec;
+
+use super::{Param, Settings, ENCODED_LEN};
+use crate::midi::sysex::{ALL_CALL, SYSEX_END, SYSEX_START};
+
+/// Manufacturer ID for non-commercial use
+const MANUFACTURER: u8 = 0x7d;
+
+const GET: u8 = 0x01;
+const SET: u8 = 0x02;
+const DUMP: u8 = 0x03;
+const RESTORE: u8 = 0x04;
+const DEFAULTS: u8 = 0x05;
+const LEARN: u8 = 0x06;
+const CANCEL_LEARN: u8 = 0x07;
+const CALIBRATE: u8 = 0x08;
+const FINISH_CALIBRATION: u8 = 0x09;
+
+const VALUE: u8 = 0x11;
+const DUMP_DATA: u8 = 0x13;
+const ACK: u8 = 0x14;
+const NAK: u8 = 0x7f;
+
+/// Longest request, a restore
+pub const MAX_REQUEST_LEN: usize = 4 + ENCODED_LEN + 1;
+/// Longest reply, a dump
+pub const MAX_REPLY_LEN: usize = 4 + ENCODED_LEN + 1;
+
+/// Reasons a request is refused, sent in the `7F` reply
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+#[repr(u8)]
+pub enum Error {
+    UnknownCommand = 1,
+    UnknownParam = 2,
+    /// The value is out of range, or the restored settings are not valid
+    InvalidValue = 3,
+    /// The request is too short or too long
+    Malformed = 4,
+}
+
+/// Outcome of a request
+pub struct Response {
+    /// The SysEx reply to send
+    pub reply: Vec<u8, MAX_REPLY_LEN>,
+    /// Whether the settings were changed (and should be stored)
+    pub changed: bool,
+    /// `Some(true)` to start MIDI learn, `Some(false)` to cancel it
+    pub learn: Option<bool>,
+    /// `Some(true)` to start calibration, `Some(false)` to finish it
+    pub calibrate: Option<bool>,
+}
+
+/// Handles a request addressed to `device_id`, `msg` is the complete SysEx message
+///
+/// Controls can be bound to CC numbers up to `max_cc`. Returns `None` for messages not
+/// meant for this device.
+pub fn handle(settings: &mut Settings, device_id: u8, max_cc: u8, msg: &[u8]) -> Option<Response> {
+    let (device, command, data) = match msg {
+        [SYSEX_START, MANUFACTURER, device, command, data @ .., SYSEX_END] => {
+            (*device, *command, data)
+        }
+        _ => return None,
+    };
+    if device != device_id && device != ALL_CALL && device_id != ALL_CALL {
+        return None;
+    }
+
+    let mut learn = None;
+    let mut calibrate = None;
+    let mut reply = Vec::new();
+    reply
+        .extend_from_slice(&[SYSEX_START, MANUFACTURER, device_id])
+        .ok();
+    let result = match command {
+        GET | SET => value(settings, command, data, max_cc, &mut reply),
+        DUMP if data.is_empty() => {
+            reply.push(DUMP_DATA).ok();
+            reply.extend_from_slice(&settings.encode()).ok();
+            Ok(false)
+        }
+        RESTORE => match Settings::decode(data) {
+            Some(restored) if !restored.cc_within(max_cc) => Err(Error::InvalidValue),
+            Some(restored) => {
+                *settings = restored;
+                reply.extend_from_slice(&[ACK, command]).ok();
+                Ok(true)
+            }
+            None if data.len() != ENCODED_LEN => Err(Error::Malformed),
+            None => Err(Error::InvalidValue),
+        },
+        DEFAULTS if data.is_empty() => {
+            *settings = Settings::default();
+            reply.extend_from_slice(&[ACK, command]).ok();
+            Ok(true)
+        }
+        LEARN | CANCEL_LEARN if data.is_empty() => {
+            learn = Some(command == LEARN);
+            reply.extend_from_slice(&[ACK, command]).ok();
+            Ok(false)
+        }
+        CALIBRATE | FINISH_CALIBRATION if data.is_empty() => {
+            calibrate = Some(command == CALIBRATE);
+            reply.extend_from_slice(&[ACK, command]).ok();
+            Ok(false)
+        }
+        DUMP | DEFAULTS | LEARN | CANCEL_LEARN | CALIBRATE | FINISH_CALIBRATION => {
+            Err(Error::Malformed)
+        }
+        _ => Err(Error::UnknownCommand),
+    };
+
+    let changed = match result {
+        Ok(changed) => changed,
+        Err(error) => {
+            reply.truncate(3);
+            reply.extend_from_slice(&[NAK, command, error as u8]).ok();
+            false
+        }
+    };
+    reply.push(SYSEX_END).ok();
+    Some(Response {
+        reply,
+        changed,
+        learn,
+        calibrate,
+    })
+}
+
+// Gets or sets a parameter, replying with its value
+fn value(
+    settings: &mut Settings,
+    command: u8,
+    data: &[u8],
+    max_cc: u8,
+    reply: &mut Vec<u8, MAX_REPLY_LEN>,
+) -> Result<bool, Error> {
+    let (param_id, index, new) = match (command, data) {
+        (GET, &[param, index]) => (param, index, None),
+        (SET, &[param, index, lsb, msb]) => (param, index, Some(lsb as u16 | (msb as u16) << 7)),
+        _ => return Err(Error::Malformed),
+    };
+    let param = Param::from_u8(param_id).ok_or(Error::UnknownParam)?;
+    let index = index as usize;
+    if let Some(new) = new {
+        if param == Param::Cc && new > max_cc as u16 {
+            return Err(Error::InvalidValue);
+        }
+        settings.set(param, index, new).ok_or(Error::InvalidValue)?;
+    }
+    let value = settings.get(param, index).ok_or(Error::InvalidValue)?;
+    reply
+        .extend_from_slice(&[
+            VALUE,
+            param_id,
+            index as u8,
+            (value & 0x7f) as u8,
+            (value >> 7 & 0x7f) as u8,
+        ])
+        .ok();
+    Ok(new.is_some())
+}
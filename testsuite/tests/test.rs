@@ -7,7 +7,24 @@ use f103_rtic as _; // memory layout + panic handler
 // feature)
 #[defmt_test::tests]
 mod tests {
-    use defmt::{assert, assert_eq};
+    use defmt::{assert, assert_eq, panic};
+    use f103_rtic::buttons::{Action, Button, ButtonEvent, Mapping};
+    use f103_rtic::filter::{Filter, Hysteresis, Median, MovingAverage};
+    use f103_rtic::midi::{
+        arpeggiator::{ArpMode, Arpeggiator},
+        clock::{ClockEvent, ClockFollower, ClockMaster, Position, PPQN},
+        parser::PacketParser,
+        rpn,
+        sequencer::{Sequencer, Step},
+        sysex, EventPacket, Identity, ManufacturerId, MidiMessage, OverflowPolicy, ParameterChange,
+        ParameterKind, ParameterTracker, SysExError, SysExReceiver, TxQueue, TX_QUEUE_LEN,
+    };
+    use f103_rtic::pickup::Pickup;
+    use f103_rtic::settings::{
+        calibration::Calibration,
+        learn::{Learn, LearnState},
+        protocol, Curve, Settings, MAX_CC, MAX_CC_14BIT,
+    };
 
     #[test]
     fn assert_true() {
@@ -18,4 +35,656 @@ mod tests {
     fn assert_eq() {
         assert_eq!(24, 42, "TODO: write actual tests")
     }
+
+    #[test]
+    fn midi_message_roundtrip() {
+        let messages = [
+            MidiMessage::NoteOn {
+                channel: 3,
+                note: 60,
+                velocity: 100,
+            },
+            MidiMessage::ProgramChange {
+                channel: 15,
+                program: 7,
+            },
+            MidiMessage::PitchBend {
+                channel: 0,
+                value: 0x3fff,
+            },
+            MidiMessage::SongPositionPointer(1000),
+            MidiMessage::TuneRequest,
+            MidiMessage::TimingClock,
+        ];
+        for msg in messages {
+            let packet = EventPacket::from_message(2, &msg);
+            assert_eq!(packet.cable(), 2);
+            assert_eq!(packet.message(), Some(msg));
+        }
+        assert_eq!(
+            EventPacket::from(MidiMessage::TimingClock).0,
+            [0x0f, 0xf8, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn sysex_roundtrip() {
+        let msg = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+        let mut cins = [0; 2];
+        let mut rx: SysExReceiver<8> = SysExReceiver::new();
+        let mut complete = false;
+        for (i, packet) in sysex::packets(0, &msg).enumerate() {
+            cins[i] = packet.cin();
+            if let Some(data) = rx.push(&packet).unwrap() {
+                assert_eq!(data, &msg[..]);
+                complete = true;
+            }
+        }
+        assert_eq!(cins, [0x4, 0x7]);
+        assert!(complete);
+
+        let mut small: SysExReceiver<4> = SysExReceiver::new();
+        let mut packets = sysex::packets(0, &msg);
+        assert_eq!(small.push(&packets.next().unwrap()), Ok(None));
+        assert_eq!(
+            small.push(&packets.next().unwrap()),
+            Err(SysExError::Overflow)
+        );
+    }
+
+    #[test]
+    fn parser_running_status_and_realtime() {
+        // note on with running status, a clock in the middle of the second note,
+        // a SysEx interrupted by a tune request and a stray data byte
+        let stream = [
+            0x90, 60, 100, 62, 0xf8, 100, 0xf0, 0x7d, 0x01, 0x02, 0x03, 0xf6, 0x10,
+        ];
+        let expected = [
+            [0x09, 0x90, 60, 100],
+            [0x0f, 0xf8, 0, 0],
+            [0x09, 0x90, 62, 100],
+            [0x04, 0xf0, 0x7d, 0x01],
+            [0x07, 0x02, 0x03, 0xf7],
+            [0x05, 0xf6, 0, 0],
+        ];
+        let mut parser = PacketParser::new(0);
+        let mut count = 0;
+        for packet in stream.iter().flat_map(|byte| parser.feed(*byte)) {
+            assert_eq!(packet.0, expected[count]);
+            count += 1;
+        }
+        assert_eq!(count, expected.len());
+    }
+
+    #[test]
+    fn nrpn_tracking() {
+        let change = ParameterChange {
+            channel: 5,
+            kind: ParameterKind::NonRegistered,
+            number: 0x1234,
+            value: 0x0abc,
+        };
+        let mut tracker = ParameterTracker::new();
+        let mut received = [None; 6];
+        for (msg, received) in change.messages().iter().zip(&mut received) {
+            *received = tracker.feed(msg);
+        }
+        // a single event with the complete value, on the data entry LSB
+        assert_eq!(received, [None, None, None, Some(change), None, None]);
+        // the null RPN ends the sequence
+        let increment = MidiMessage::ControlChange {
+            channel: 5,
+            control: rpn::DATA_INCREMENT,
+            value: 0,
+        };
+        assert_eq!(tracker.feed(&increment), None);
+
+        // senders of 7 bit values complete them with the MSB
+        let mut tracker = ParameterTracker::new().with_msb_only(true);
+        let cc = |control, value| MidiMessage::ControlChange {
+            channel: 0,
+            control,
+            value,
+        };
+        assert_eq!(tracker.feed(&cc(rpn::RPN_MSB, 0)), None);
+        assert_eq!(tracker.feed(&cc(rpn::RPN_LSB, 0)), None);
+        let bend_range = tracker.feed(&cc(rpn::DATA_ENTRY_MSB, 12)).unwrap();
+        assert_eq!(bend_range.number, rpn::PITCH_BEND_SENSITIVITY);
+        assert_eq!(bend_range.value, 12 << 7);
+        assert_eq!(tracker.feed(&cc(rpn::DATA_ENTRY_LSB, 50)), None);
+    }
+
+    #[test]
+    fn clock_follower() {
+        let mut master = ClockMaster::new(120);
+        let mut follower = ClockFollower::new();
+        let mut now: u32 = u32::MAX - 100_000; // timestamps wrap around
+        assert_eq!(follower.feed(&master.start(), now), Some(ClockEvent::Start));
+        // one beat at 120 bpm is 500 ms, with some jitter on the arrival times
+        for i in 0..4 * PPQN {
+            let (msg, interval) = master.tick();
+            let jitter = [0, 300, 0, 0, 700, 100][i as usize % 6];
+            let event = follower.feed(&msg, now.wrapping_add(jitter));
+            assert_eq!(event, Some(ClockEvent::Tick(Position { clocks: i })));
+            now = now.wrapping_add(interval);
+        }
+        assert_eq!(now, (u32::MAX - 100_000).wrapping_add(4 * 500_000));
+        assert_eq!(follower.bpm(), Some(120));
+        assert_eq!(follower.position().beat(), 4);
+
+        let stop = follower.feed(&master.stop(), now);
+        assert_eq!(stop, Some(ClockEvent::Stop(Position { clocks: 96 })));
+        let locate = follower.feed(&MidiMessage::SongPositionPointer(8), now);
+        assert_eq!(locate, Some(ClockEvent::Locate(Position { clocks: 48 })));
+        assert_eq!(follower.position().beat(), 2);
+        // no clock for longer than at the slowest tempo, the tempo is unknown
+        follower.feed(&MidiMessage::TimingClock, now.wrapping_add(200_000));
+        assert_eq!(follower.bpm(), None);
+    }
+
+    #[test]
+    fn sequencer_timing() {
+        let mut seq = Sequencer::new(0, 120);
+        seq.set_swing(60);
+        // 16ths at 120 bpm are 125 ms, with swing the pairs are 150 + 100 ms
+        seq.set_pattern(&[
+            Step::note(60, 100),
+            Step::REST,
+            Step::note(64, 100).gate(200),
+            Step::note(64, 90),
+        ]);
+        let start = u32::MAX - 200_000; // timestamps wrap around
+        seq.start(start);
+        let mut events = [(0, 0, false); 6];
+        let mut len = 0;
+        let mut now = start;
+        while len < events.len() {
+            while let Some(msg) = seq.poll(now) {
+                let event = match msg {
+                    MidiMessage::NoteOn { note, .. } => (now.wrapping_sub(start), note, true),
+                    MidiMessage::NoteOff { note, .. } => (now.wrapping_sub(start), note, false),
+                    _ => panic!("unexpected {}", msg),
+                };
+                events[len] = event;
+                len += 1;
+            }
+            now = seq.next_due(now).unwrap();
+        }
+        let expected = [
+            (0, 60, true),
+            (62_500, 60, false),
+            (250_000, 64, true),
+            // the long note is ended before the next one on the same key
+            (400_000, 64, false),
+            (400_000, 64, true),
+            (462_500, 64, false),
+        ];
+        assert_eq!(events, expected);
+        assert_eq!(seq.position(), 0);
+
+        // stopping ends the notes right away
+        seq.poll(now);
+        assert_eq!(seq.poll(now), None);
+        seq.stop();
+        assert!(matches!(
+            seq.poll(now),
+            Some(MidiMessage::NoteOff { note: 60, .. })
+        ));
+        assert_eq!(seq.poll(now), None);
+        assert_eq!(seq.next_due(now), None);
+    }
+
+    #[test]
+    fn arpeggiator_modes() {
+        // note-ons of the first 11 steps, and the time of the last one
+        fn play(arp: &mut Arpeggiator) -> ([u8; 11], u32) {
+            let mut notes = [0; 11];
+            let mut len = 0;
+            let mut now = 0;
+            while len < notes.len() {
+                while let Some(msg) = arp.poll(now) {
+                    if let MidiMessage::NoteOn { note, .. } = msg {
+                        notes[len] = note;
+                        len += 1;
+                    }
+                }
+                now = arp.next_due(now).unwrap();
+            }
+            (notes, now)
+        }
+
+        let mut arp = Arpeggiator::new(0, 120);
+        arp.set_octaves(2);
+        arp.set_mode(ArpMode::UpDown);
+        for note in [64, 60, 67] {
+            arp.note_on(note, 100, 0);
+        }
+        let (notes, now) = play(&mut arp);
+        assert_eq!(notes, [60, 64, 67, 72, 76, 79, 76, 72, 67, 64, 60]);
+        // eighths at 120 bpm, the last note-off is due half a step later
+        assert_eq!(now, 10 * 250_000 + 125_000);
+
+        arp.release_all();
+        while arp.poll(now).is_some() {}
+        assert_eq!(arp.next_due(now), None);
+
+        arp.set_mode(ArpMode::AsPlayed);
+        for note in [64, 60, 67] {
+            arp.note_on(note, 100, 0);
+        }
+        let (notes, _) = play(&mut arp);
+        assert_eq!(notes, [64, 60, 67, 76, 72, 79, 64, 60, 67, 76, 72]);
+    }
+
+    #[test]
+    fn identity_reply() {
+        let request = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+        assert!(sysex::is_identity_request(&request, 0x10));
+        assert!(!sysex::is_identity_request(
+            &[0xf0, 0x7e, 0x11, 0x06, 0x01, 0xf7],
+            0x10
+        ));
+
+        let identity = Identity {
+            manufacturer: ManufacturerId::Extended(0x20, 0x33),
+            family: 0x0181,
+            model: 0x0002,
+            version: [1, 2, 3, 0],
+        };
+        let reply = identity.reply(0x10);
+        let expected = [
+            0xf0, 0x7e, 0x10, 0x06, 0x02, 0x00, 0x20, 0x33, 0x01, 0x03, 0x02, 0x00, 1, 2, 3, 0,
+            0xf7,
+        ];
+        assert_eq!(&reply[..], &expected[..]);
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let note = |note: u8| EventPacket::from_message(0, &MidiMessage::note_off(0, note));
+        let cc = |control: u8, value: u8| {
+            EventPacket::from_message(
+                0,
+                &MidiMessage::ControlChange {
+                    channel: 0,
+                    control,
+                    value,
+                },
+            )
+        };
+        let fill = |queue: &mut TxQueue| {
+            for i in 0..TX_QUEUE_LEN {
+                assert!(queue.push(note(i as u8)).is_ok());
+            }
+            assert_eq!(queue.room(), 0);
+        };
+
+        // the packet sent to a full queue is refused
+        let mut queue = TxQueue::new(OverflowPolicy::DropNewest);
+        fill(&mut queue);
+        assert!(queue.push(note(100)).is_err());
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.len(), TX_QUEUE_LEN);
+        assert!(queue.iter().enumerate().all(|(i, p)| *p == note(i as u8)));
+
+        // the oldest packet makes room
+        let mut queue = TxQueue::new(OverflowPolicy::DropOldest);
+        fill(&mut queue);
+        assert!(queue.push(note(100)).is_ok());
+        assert!(queue.push(note(101)).is_ok());
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.len(), TX_QUEUE_LEN);
+        assert_eq!(queue.iter().next(), Some(&note(2)));
+        assert_eq!(queue.iter().last(), Some(&note(101)));
+
+        // queued SysEx is kept whole, the oldest other packet makes room
+        let mut queue = TxQueue::new(OverflowPolicy::DropOldest);
+        let dump = [0xf0, 0x7d, 1, 2, 3, 4, 0xf7];
+        for packet in sysex::packets(0, &dump) {
+            assert!(queue.push(packet).is_ok());
+        }
+        for i in 3..TX_QUEUE_LEN {
+            assert!(queue.push(note(i as u8)).is_ok());
+        }
+        assert!(queue.push(note(100)).is_ok());
+        assert!(sysex::packets(0, &dump).eq(queue.iter().take(3).copied()));
+        assert_eq!(queue.iter().nth(3), Some(&note(4)));
+        // a queue of SysEx only refuses the new packet
+        let mut dump = [0x7d; 3 * TX_QUEUE_LEN];
+        dump[0] = 0xf0;
+        dump[3 * TX_QUEUE_LEN - 1] = 0xf7;
+        queue.clear();
+        for packet in sysex::packets(0, &dump) {
+            assert!(queue.push(packet).is_ok());
+        }
+        assert!(queue.push(note(100)).is_err());
+        assert!(sysex::packets(0, &dump).eq(queue.iter().copied()));
+
+        // repeated CCs replace the queued value, in its place
+        let mut queue = TxQueue::new(OverflowPolicy::Coalesce);
+        assert!(queue.push(cc(7, 1)).is_ok());
+        assert!(queue.push(cc(10, 1)).is_ok());
+        for value in 2..100 {
+            assert!(queue.push(cc(7, value)).is_ok());
+        }
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.iter().next(), Some(&cc(7, 99)));
+
+        // the (N)RPN controllers are kept as they are
+        assert!(queue.push(cc(rpn::DATA_ENTRY_MSB, 1)).is_ok());
+        assert!(queue.push(cc(rpn::DATA_ENTRY_MSB, 2)).is_ok());
+        assert_eq!(queue.len(), 4);
+
+        // a full queue still takes new values of queued CCs, and refuses anything else
+        for i in 4..TX_QUEUE_LEN {
+            assert!(queue.push(note(i as u8)).is_ok());
+        }
+        assert!(queue.push(cc(10, 127)).is_ok());
+        assert!(queue.push(cc(11, 127)).is_err());
+        assert!(queue.push(note(100)).is_err());
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.len(), TX_QUEUE_LEN);
+        assert_eq!(queue.iter().nth(1), Some(&cc(10, 127)));
+        assert_eq!(queue.pop(), Some(cc(7, 99)));
+    }
+
+    #[test]
+    fn settings_protocol() {
+        let mut settings = Settings::default();
+        // set CC 74 on control 2
+        let set = [0xf0, 0x7d, 0x7f, 0x02, 0x01, 0x02, 74, 0x00, 0xf7];
+        let response = protocol::handle(&mut settings, 0x7f, MAX_CC, &set).unwrap();
+        assert_eq!(
+            &response.reply[..],
+            &[0xf0, 0x7d, 0x7f, 0x11, 0x01, 0x02, 74, 0x00, 0xf7]
+        );
+        assert!(response.changed);
+        assert_eq!(settings.controls[2].cc, 74);
+
+        // channel 16 does not exist
+        let set = [0xf0, 0x7d, 0x7f, 0x02, 0x00, 0x00, 16, 0x00, 0xf7];
+        let response = protocol::handle(&mut settings, 0x7f, MAX_CC, &set).unwrap();
+        assert_eq!(
+            &response.reply[..],
+            &[0xf0, 0x7d, 0x7f, 0x7f, 0x02, 0x03, 0xf7]
+        );
+        assert!(!response.changed);
+
+        // a dump restores the same settings
+        let dump =
+            protocol::handle(&mut settings, 0x7f, MAX_CC, &[0xf0, 0x7d, 0x7f, 0x03, 0xf7]).unwrap();
+        assert_eq!(dump.reply[3], 0x13);
+        let mut restore = dump.reply.clone();
+        restore[3] = 0x04;
+        let mut restored = Settings::default();
+        let response = protocol::handle(&mut restored, 0x7f, MAX_CC, &restore).unwrap();
+        assert_eq!(&response.reply[..], &[0xf0, 0x7d, 0x7f, 0x14, 0x04, 0xf7]);
+        assert_eq!(restored, settings);
+
+        // CC 74 has no 14 bit pair
+        let mut restored = Settings::default();
+        let response = protocol::handle(&mut restored, 0x7f, MAX_CC_14BIT, &restore).unwrap();
+        assert_eq!(
+            &response.reply[..],
+            &[0xf0, 0x7d, 0x7f, 0x7f, 0x04, 0x03, 0xf7]
+        );
+        assert_eq!(restored, Settings::default());
+        let set = [0xf0, 0x7d, 0x7f, 0x02, 0x01, 0x03, 74, 0x00, 0xf7];
+        let response = protocol::handle(&mut settings, 0x7f, MAX_CC_14BIT, &set).unwrap();
+        assert_eq!(
+            &response.reply[..],
+            &[0xf0, 0x7d, 0x7f, 0x7f, 0x02, 0x03, 0xf7]
+        );
+
+        // not for us
+        assert!(
+            protocol::handle(&mut settings, 0x01, MAX_CC, &[0xf0, 0x7d, 0x02, 0x03, 0xf7])
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn midi_learn() {
+        let mut settings = Settings::default();
+        let mut learn = Learn::new();
+        let cc = MidiMessage::ControlChange {
+            channel: 3,
+            control: 74,
+            value: 0,
+        };
+        // nothing is bound before a control is moved
+        learn.start();
+        assert_eq!(learn.feed(&mut settings, &cc), None);
+        assert_eq!(learn.state(), LearnState::Control);
+
+        learn.control_moved(1);
+        learn.control_moved(4);
+        assert_eq!(learn.feed(&mut settings, &cc), Some(4));
+        assert!(!learn.is_active());
+        assert_eq!(settings.controls[4].cc, 74);
+        assert_eq!(settings.control_channel(4), 3);
+        assert_eq!(settings.control_channel(1), 0);
+
+        // with 14 bit values only CCs below 32 are bound
+        let mut learn = Learn::new().with_max_cc(MAX_CC_14BIT);
+        learn.start();
+        learn.control_moved(5);
+        assert_eq!(learn.feed(&mut settings, &cc), None);
+        let msb = MidiMessage::ControlChange {
+            channel: 3,
+            control: 7,
+            value: 0,
+        };
+        assert_eq!(learn.feed(&mut settings, &msb), Some(5));
+        assert_eq!(settings.controls[5].cc, 7);
+
+        // learning is started and cancelled over SysEx
+        let response =
+            protocol::handle(&mut settings, 0x7f, MAX_CC, &[0xf0, 0x7d, 0x7f, 0x06, 0xf7]).unwrap();
+        assert_eq!(&response.reply[..], &[0xf0, 0x7d, 0x7f, 0x14, 0x06, 0xf7]);
+        assert_eq!(response.learn, Some(true));
+    }
+
+    #[test]
+    fn calibration_curves() {
+        let mut settings = Settings::default();
+        let mut calibration = Calibration::new();
+        calibration.start();
+        // control 0 is turned from end to end, control 1 is barely touched
+        for raw in [2000, 180, 95, 100, 2500, 3900, 3990, 3985] {
+            calibration.feed(0, raw);
+            calibration.feed(1, raw / 8 + 1000);
+        }
+        assert_eq!(calibration.finish(&mut settings), 1);
+        assert_eq!(
+            (settings.controls[0].min, settings.controls[0].max),
+            (103, 3982)
+        );
+        assert_eq!(
+            (settings.controls[1].min, settings.controls[1].max),
+            (0, 4095)
+        );
+        // the ends of the pot reach the ends of the range
+        assert_eq!(settings.response(0, 95), 0);
+        assert_eq!(settings.response(0, 3990), 4095);
+
+        // restoring checks the range
+        let mut dump = settings.encode();
+        assert_eq!(Settings::decode(&dump), Some(settings));
+        dump[3 + 5..3 + 9].copy_from_slice(&[0x7f, 0x1f, 0x00, 0x00]);
+        assert_eq!(Settings::decode(&dump), None);
+        // an empty range set in code leaves the readings as they are
+        settings.controls[0].min = 3000;
+        settings.controls[0].max = 3000;
+        assert_eq!(settings.controls[0].calibrate(1234), 1234);
+        settings.controls[0].max = 1000;
+        assert_eq!(settings.controls[0].calibrate(1234), 1234);
+
+        assert_eq!(Curve::SCurve.apply(0, &settings.lut), 0);
+        assert_eq!(Curve::SCurve.apply(2048, &settings.lut), 2048);
+        assert!(Curve::SCurve.apply(512, &settings.lut) < 512);
+        assert_eq!(Curve::SCurve.apply(4095, &settings.lut), 4095);
+
+        // the default table is linear, an inverted one turns the pot around
+        assert!(Curve::Custom.apply(1000, &settings.lut).abs_diff(1000) <= 1);
+        for (i, point) in settings.lut.iter_mut().enumerate() {
+            *point = 4095 - (i * 4095 / 16) as u16;
+        }
+        assert_eq!(Curve::Custom.apply(0, &settings.lut), 4095);
+        assert_eq!(Curve::Custom.apply(4095, &settings.lut), 0);
+        assert!(Curve::Custom.apply(1024, &settings.lut).abs_diff(3071) <= 1);
+    }
+
+    #[test]
+    fn pickup_takeover() {
+        let mut pickup = Pickup::<2>::new(1);
+        // without a host value the knob is followed
+        assert!(pickup.update(0, 20));
+        assert!(!pickup.is_waiting());
+
+        // the host moved to 64, the knob at 20 waits until it crosses that
+        pickup.set_host_value(0, 64);
+        assert!(pickup.is_waiting());
+        assert!(!pickup.update(0, 40));
+        assert!(!pickup.update(0, 60));
+        assert!(pickup.update(0, 70));
+        assert!(!pickup.is_waiting());
+        assert_eq!(pickup.host_value(0), Some(70));
+
+        // echoes of sent values, or values close to the knob, keep it picked up
+        pickup.set_host_value(0, 69);
+        assert!(pickup.is_picked_up(0));
+        assert!(pickup.update(1, 5));
+
+        // coming from above
+        pickup.set_host_value(1, 100);
+        assert!(!pickup.update(1, 50));
+        pickup.set_host_value(1, 30);
+        assert!(!pickup.update(1, 40));
+        assert!(pickup.update(1, 31));
+    }
+
+    #[test]
+    fn button_gestures() {
+        let toggle = Action::ToggleCc { channel: 0, cc: 80 };
+        let mut button = Button::new(Mapping {
+            press: Some(toggle),
+            long: Some(Action::ProgramChange {
+                channel: 0,
+                program: 3,
+            }),
+            double: Some(Action::Note {
+                channel: 0,
+                note: 60,
+                velocity: 100,
+            }),
+        });
+        // feeds the button every 2 ms from `at` to `until`
+        let events = |button: &mut Button, pressed, at: u32, until: u32| {
+            let mut events = [None; 4];
+            let mut n = 0;
+            for now in (at..until).step_by(2_000) {
+                if let Some(event) = button.update(pressed, now) {
+                    events[n] = Some(event);
+                    n += 1;
+                }
+            }
+            events
+        };
+
+        // bouncing contacts, then a short press
+        button.update(true, 0);
+        button.update(false, 2_000);
+        assert_eq!(
+            events(&mut button, true, 4_000, 100_000)[0],
+            Some(ButtonEvent::Pressed)
+        );
+        assert_eq!(
+            events(&mut button, false, 100_000, 200_000)[0],
+            Some(ButtonEvent::Released)
+        );
+        assert_eq!(
+            events(&mut button, false, 200_000, 600_000)[0],
+            Some(ButtonEvent::Short)
+        );
+
+        // a long press
+        let held = events(&mut button, true, 600_000, 1_200_000);
+        assert_eq!(
+            held[..2],
+            [Some(ButtonEvent::Pressed), Some(ButtonEvent::Long)]
+        );
+        assert_eq!(
+            events(&mut button, false, 1_200_000, 2_000_000)[..2],
+            [Some(ButtonEvent::Released), None]
+        );
+
+        // the press action waits for the other gestures, the double press plays a note
+        let mut messages = [None; 4];
+        let mut n = 0;
+        for (pressed, at, until) in [
+            (true, 2_000_000, 2_100_000),
+            (false, 2_100_000, 2_200_000),
+            (true, 2_200_000, 2_300_000),
+            (false, 2_300_000, 2_400_000),
+        ] {
+            for now in (at..until).step_by(2_000) {
+                for msg in button.poll(pressed, now) {
+                    messages[n] = Some(msg);
+                    n += 1;
+                }
+            }
+        }
+        assert_eq!(
+            messages[..2],
+            [
+                Some(MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                }),
+                Some(MidiMessage::NoteOff {
+                    channel: 0,
+                    note: 60,
+                    velocity: 0
+                }),
+            ]
+        );
+        assert_eq!(n, 2);
+    }
+
+    // 12 bit readings of a pot at rest (with a spike), then turned up a bit
+    const POT_TRACE: [u16; 32] = [
+        2049, 2051, 2047, 2050, 2052, 2048, 2049, 4095, 2050, 2046, 2049, 2051, 2050, 2048, 2052,
+        2049, 2060, 2074, 2081, 2097, 2110, 2118, 2133, 2141, 2150, 2149, 2152, 2148, 2151, 2150,
+        2149, 2151,
+    ];
+
+    #[test]
+    fn filter_traces() {
+        let mut median = Median::<u16, 3>::new();
+        let mut average = MovingAverage::<u16, 4>::new();
+        let mut hysteresis = Hysteresis::new(4u16).with_limits(0, 4095);
+        let mut changes = [0u16; 32];
+        let mut len = 0;
+        for sample in POT_TRACE {
+            let smooth = average.update(median.update(sample));
+            // the spike is gone
+            assert!(smooth < 2200);
+            if let Some(value) = hysteresis.update(smooth) {
+                changes[len] = value;
+                len += 1;
+            }
+        }
+        // one change at rest, a rising stream while turned, none at rest again
+        let changes = &changes[..len];
+        assert_eq!(changes[0], 2049);
+        assert!(changes.windows(2).all(|w| w[0] < w[1]));
+        assert!(changes[len - 1] >= 2140);
+        assert!(len < 16);
+
+        // the ends of the range are reached despite the band
+        assert_eq!(hysteresis.update(4095), Some(4095));
+        assert_eq!(hysteresis.update(4093), None);
+    }
 }